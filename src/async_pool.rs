@@ -10,9 +10,12 @@ use crate::signal;
 
 type JobFunc = dyn Fn() -> Option<error::Error> + 'static + Send + Sync;
 type Job = Box<JobFunc>;
+type PollingJobFunc = dyn Fn(&signal::Emitter) -> Option<error::Error> + 'static + Send + Sync;
 
 struct PollingJob {
-    job: Box<JobFunc>,
+    job: Box<PollingJobFunc>,
+    // events of the job are marked with its name
+    emitter: signal::Emitter,
     // might not need to be an atomic, since we are passing the whole job object as a arc<mutex>>
     running: AtomicBool,
     waiting: bool,
@@ -21,9 +24,15 @@ struct PollingJob {
 }
 
 impl PollingJob {
-    fn new(job: Box<JobFunc>, waiting: bool, timeout: Duration) -> Self {
+    fn new(
+        job: Box<PollingJobFunc>,
+        emitter: signal::Emitter,
+        waiting: bool,
+        timeout: Duration,
+    ) -> Self {
         Self {
             job,
+            emitter,
             running: AtomicBool::new(false),
             waiting,
             timeout,
@@ -46,10 +55,43 @@ impl Capacity {
     }
 }
 
+// how late the polling jobs start compared to their timeout, in seconds
+pub struct Histogram {
+    pub bounds: Vec<f64>,
+    pub counts: Vec<u64>,
+    pub sum: f64,
+    pub count: u64,
+}
+
+impl Histogram {
+    fn new() -> Self {
+        let bounds = vec![0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0];
+
+        Self {
+            counts: vec![0; bounds.len()],
+            bounds,
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        for (bound, count) in self.bounds.iter().zip(self.counts.iter_mut()) {
+            if value <= *bound {
+                *count += 1;
+            }
+        }
+
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
 struct AsyncState {
     queue: (Mutex<VecDeque<Message>>, Condvar),
     jobs: Mutex<Vec<Arc<Mutex<PollingJob>>>>,
     capacity: Mutex<Capacity>,
+    lateness: Arc<Mutex<Histogram>>,
     signal: Arc<Mutex<signal::Signal>>,
 }
 
@@ -59,6 +101,7 @@ impl AsyncState {
             queue: (Mutex::new(VecDeque::new()), Condvar::new()),
             jobs: Mutex::new(Vec::new()),
             capacity: Mutex::new(Capacity::new()),
+            lateness: Arc::new(Mutex::new(Histogram::new())),
             signal,
         }
     }
@@ -82,7 +125,7 @@ impl WaitableWorker {
         let t = thread::spawn(move || {
             {
                 let mut capacity = state.capacity.lock().unwrap();
-                capacity.capacity = capacity.capacity + 1;
+                capacity.capacity += 1;
             }
             loop {
                 let message: Message;
@@ -100,23 +143,29 @@ impl WaitableWorker {
 
                 {
                     let mut capacity = state.capacity.lock().unwrap();
-                    capacity.load = capacity.load + 1;
+                    capacity.load += 1;
                 }
 
                 match message {
                     Message::NewJob(job) => {
-                        job();
+                        if let Some(e) = job() {
+                            state.signal.lock().unwrap().notify(event::Event::error(e));
+                        }
                     }
                     Message::NewPollingJob(polling_job_mutex) => {
                         let mut job = polling_job_mutex.lock().unwrap();
 
-                        job.last_t = Instant::now();
+                        let now = Instant::now();
+                        let late = (now - job.last_t).saturating_sub(job.timeout);
+                        state.lateness.lock().unwrap().observe(late.as_secs_f64());
+
+                        job.last_t = now;
                         job.running.store(true, Ordering::SeqCst);
-                        let r = (job.job)();
+                        let r = (job.job)(&job.emitter);
                         job.running.store(false, Ordering::SeqCst);
 
                         if let Some(e) = r {
-                            state.signal.lock().unwrap().notify(event::Event::Error(e));
+                            job.emitter.emit(event::Event::error(e));
                         }
                     }
                     Message::Shutdown => {
@@ -126,18 +175,18 @@ impl WaitableWorker {
 
                 {
                     let mut capacity = state.capacity.lock().unwrap();
-                    capacity.load = capacity.load - 1;
+                    capacity.load -= 1;
                 }
             }
 
             {
                 let mut capacity = state.capacity.lock().unwrap();
-                capacity.capacity = capacity.capacity - 1;
+                capacity.capacity -= 1;
                 state
                     .signal
                     .lock()
                     .unwrap()
-                    .notify(event::Event::Log(String::from(
+                    .notify(event::Event::log(String::from(
                         "Shutting down waiting worker",
                     )));
             }
@@ -158,7 +207,7 @@ impl PollingWorker {
                 .signal
                 .lock()
                 .unwrap()
-                .notify(event::Event::Log(String::from("Starting polling worker")));
+                .notify(event::Event::log(String::from("Starting polling worker")));
 
             loop {
                 //println!("LITERAL START OF LOOP");
@@ -201,7 +250,7 @@ impl PollingWorker {
                             // if we get to here we would need to spawn another thread to handle this,
                             // increase our thread count
                             // time is of the essense here, need to be thought out how to be handled
-                            state.signal.lock().unwrap().notify(event::Event::LogError(String::from("This is a big issue, there is no designated worker to take care of this task and possible other scenarios")));
+                            state.signal.lock().unwrap().notify(event::Event::new(event::Severity::Error, event::Payload::Log(String::from("This is a big issue, there is no designated worker to take care of this task and possible other scenarios"))));
                         }
                     }
                 }
@@ -211,7 +260,7 @@ impl PollingWorker {
 
                 //println!("TIME PASSED FOR TICK {}", dt.as_millis());
                 if dt > resolution {
-                    state.signal.lock().unwrap().notify(event::Event::LogError(String::from("Got dt biggen than the resolution this is an issue, posslby start another timer/job thread")));
+                    state.signal.lock().unwrap().notify(event::Event::new(event::Severity::Error, event::Payload::Log(String::from("Got dt biggen than the resolution this is an issue, posslby start another timer/job thread"))));
                     panic!("DT BIGGER THAN RESOLUTION");
                 }
 
@@ -231,21 +280,42 @@ enum Worker {
 
 impl Worker {
     fn join(&mut self) {
-        match (self) {
+        match self {
             Worker::Waiting(w) => {
                 if let Some(handle) = w.t.take() {
-                    handle.join();
+                    let _ = handle.join();
                 }
             }
             Worker::Polling(w) => {
                 if let Some(handle) = w.t.take() {
-                    handle.join();
+                    let _ = handle.join();
                 }
             }
         };
     }
 }
 
+/// Hands jobs to the pool from other threads.
+#[derive(Clone)]
+pub struct Submitter {
+    async_state: Arc<AsyncState>,
+}
+
+impl Submitter {
+    /// Runs `job` once on one of the workers, a returned error is emitted as an event.
+    pub fn submit<F>(&self, job: F)
+    where
+        F: Fn() -> Option<error::Error> + 'static + Send + Sync,
+    {
+        let (lock, cvar) = &self.async_state.queue;
+        lock.lock()
+            .unwrap()
+            .push_back(Message::NewJob(Box::new(job)));
+        cvar.notify_one();
+    }
+}
+
+/// Worker threads running jobs, once or every interval, and the listeners of their events.
 pub struct AsyncPool {
     workers: Vec<Worker>,
     polling_attached: AtomicBool,
@@ -255,6 +325,7 @@ pub struct AsyncPool {
 }
 
 impl AsyncPool {
+    /// Starts `count` workers, jobs are due at most `polling_resolution` late.
     pub fn new(count: usize, polling_resolution: Duration) -> Self {
         let mut workers = Vec::with_capacity(count);
 
@@ -277,26 +348,31 @@ impl AsyncPool {
         }
     }
 
+    /// Runs `job` once, see [`Submitter::submit`].
     pub fn submit<F>(&mut self, job: F)
     where
         F: Fn() -> Option<error::Error> + 'static + Send + Sync,
     {
-        let (lock, cvar) = &self.async_state.queue;
-        lock.lock()
-            .unwrap()
-            .push_back(Message::NewJob(Box::new(job)));
-        cvar.notify_one();
+        self.submitter().submit(job);
     }
 
-    pub fn attach_job<F>(&mut self, timeout: Duration, job: F)
+    /// Submits jobs to the pool from other threads.
+    pub fn submitter(&self) -> Submitter {
+        Submitter {
+            async_state: Arc::clone(&self.async_state),
+        }
+    }
+
+    /// Runs `job` every `timeout`, its events and returned errors come from `name`.
+    pub fn attach_job<F>(&mut self, name: &str, timeout: Duration, job: F)
     where
-        F: Fn() -> Option<error::Error> + 'static + Send + Sync,
+        F: Fn(&signal::Emitter) -> Option<error::Error> + 'static + Send + Sync,
     {
         let attached_result =
             self.polling_attached
                 .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst);
 
-        if let Ok(_) = attached_result {
+        if attached_result.is_ok() {
             self.workers.push(Worker::Polling(PollingWorker::new(
                 Arc::clone(&self.async_state),
                 self.polling_resolution,
@@ -306,11 +382,18 @@ impl AsyncPool {
         let mut jobs = self.async_state.jobs.lock().unwrap();
         jobs.push(Arc::new(Mutex::new(PollingJob::new(
             Box::new(job),
+            signal::Emitter::new(Some(name.to_string()), Arc::clone(&self.signal)),
             true,
             timeout,
         ))));
     }
 
+    /// How late the attached jobs started, in seconds.
+    pub fn lateness(&self) -> Arc<Mutex<Histogram>> {
+        Arc::clone(&self.async_state.lateness)
+    }
+
+    /// Calls `f` with every event of the jobs.
     pub fn connect_listener<F>(&mut self, f: F)
     where
         F: Fn(Arc<event::Event>) + 'static + Send,
@@ -318,6 +401,16 @@ impl AsyncPool {
         self.signal.lock().unwrap().connect(f);
     }
 
+    /// Calls `f` with the events matching `filter`.
+    pub fn connect_filtered_listener<P, F>(&mut self, filter: P, f: F)
+    where
+        P: Fn(&event::Event) -> bool + 'static + Send,
+        F: Fn(Arc<event::Event>) + 'static + Send,
+    {
+        self.signal.lock().unwrap().connect_filtered(filter, f);
+    }
+
+    /// Drops the jobs and stops the workers once they are idle.
     pub fn shutdown(&mut self) {
         let mut jobs = self.async_state.jobs.lock().unwrap();
         let mut queue = self.async_state.queue.0.lock().unwrap();
@@ -328,8 +421,12 @@ impl AsyncPool {
         self.workers.iter_mut().for_each(|_| {
             queue.push_back(Message::Shutdown);
         });
+
+        // the waiting workers only look at the queue once woken up
+        self.async_state.queue.1.notify_all();
     }
 
+    /// Blocks until the workers stopped.
     pub fn wait(&mut self) {
         self.workers.iter_mut().for_each(|w| {
             w.join();
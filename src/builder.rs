@@ -0,0 +1,232 @@
+use std::time::Duration;
+
+use crate::control::{Config, Context, Control};
+use crate::destination::DestConfig;
+use crate::error::Error;
+use crate::source::{Checks, Source, SourceConfig};
+
+/// Puts together the [`Config`] of a control without going through toml.
+///
+/// Options that aren't set keep the defaults of the config file.
+pub struct ControlBuilder {
+    config: Config,
+    source: Option<Box<dyn Source>>,
+}
+
+impl ControlBuilder {
+    /// A control polled every second.
+    pub fn new(name: &str) -> Self {
+        Self {
+            config: Config {
+                name: name.to_string(),
+                interval: 1000,
+                ..Default::default()
+            },
+            source: None,
+        }
+    }
+
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.config.interval = interval.as_millis() as u64;
+        self
+    }
+
+    /// One of the source types of the config file, e.g. `file`, `program` or `shared`.
+    pub fn source(mut self, src_type: &str, path: &str) -> Self {
+        self.config.src_type = Some(src_type.to_string());
+        self.config.src_path = Some(path.to_string());
+        self
+    }
+
+    /// Arguments of a `program` source, or the line of a `cpu_usage` source.
+    pub fn source_args(mut self, args: &[&str]) -> Self {
+        self.config.src_args = Some(args.iter().map(|arg| arg.to_string()).collect());
+        self
+    }
+
+    /// Several sources combined with `aggregate`, e.g. `max`, `avg` or `weighted`.
+    pub fn sources(mut self, sources: Vec<SourceConfig>, aggregate: &str) -> Self {
+        self.config.sources = Some(sources);
+        self.config.aggregate = Some(aggregate.to_string());
+        self
+    }
+
+    /// Reads a source of your own instead of one of the config file.
+    pub fn custom_source(mut self, source: Box<dyn Source>) -> Self {
+        self.source = Some(source);
+        self
+    }
+
+    /// Stale, range and spike checks of the source, not applied to a custom source.
+    pub fn checks(mut self, checks: Checks) -> Self {
+        self.config.checks = checks;
+        self
+    }
+
+    /// Adds an output written with values between `dest_min` and `dest_max`.
+    pub fn dest(self, path: &str, dest_min: i64, dest_max: i64) -> Self {
+        self.dest_config(DestConfig {
+            path: path.to_string(),
+            dest_min,
+            dest_max,
+            scale: None,
+            offset: None,
+            rpm_path: None,
+        })
+    }
+
+    pub fn dest_config(mut self, dest: DestConfig) -> Self {
+        self.config.dests.get_or_insert_with(Vec::new).push(dest);
+        self
+    }
+
+    /// Points of the curve, reading to percent.
+    pub fn points(mut self, points: &[(i64, i64)]) -> Self {
+        self.config.points = points
+            .iter()
+            .map(|&(input, percent)| vec![input, percent])
+            .collect();
+        self
+    }
+
+    /// `linear` or `step`.
+    pub fn curve(mut self, curve: &str) -> Self {
+        self.config.curve = Some(curve.to_string());
+        self
+    }
+
+    pub fn min_start_percent(mut self, percent: f64) -> Self {
+        self.config.min_start_percent = Some(percent);
+        self
+    }
+
+    pub fn min_run_percent(mut self, percent: f64) -> Self {
+        self.config.min_run_percent = Some(percent);
+        self
+    }
+
+    /// Stops the fans below this reading.
+    pub fn stop_below(mut self, reading: i64) -> Self {
+        self.config.stop_below = Some(reading);
+        self
+    }
+
+    /// Reading at which every control of the [`Context`] goes to maximum.
+    pub fn critical(mut self, reading: i64) -> Self {
+        self.config.critical = Some(reading);
+        self
+    }
+
+    /// Percent written while the source fails.
+    pub fn default_percent(mut self, percent: i32) -> Self {
+        self.config.default_dest_percent = Some(percent);
+        self
+    }
+
+    /// The config as it would be read from toml, a custom source is left out.
+    pub fn config(self) -> Config {
+        self.config
+    }
+
+    /// Opens the source and the destinations.
+    pub fn build(self, context: &Context) -> Result<Control, Error> {
+        match self.source {
+            Some(source) => Control::with_source(self.config, source, context),
+            None => Control::new(self.config, context),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Code;
+
+    #[test]
+    fn puts_together_the_config_of_the_toml_file() {
+        let config = ControlBuilder::new("cpu")
+            .interval(Duration::from_millis(500))
+            .source("program", "/usr/bin/gpu-usage")
+            .source_args(&["temp"])
+            .checks(Checks {
+                valid_max: Some(120000),
+                ..Default::default()
+            })
+            .dest("/sys/class/hwmon/hwmon0/pwm1", 0, 255)
+            .dest_config(DestConfig {
+                path: String::from("/sys/class/hwmon/hwmon0/pwm2"),
+                dest_min: 60,
+                dest_max: 200,
+                scale: Some(0.5),
+                offset: None,
+                rpm_path: None,
+            })
+            .points(&[(30, 20), (80, 100)])
+            .curve("step")
+            .min_start_percent(40.0)
+            .min_run_percent(25.0)
+            .stop_below(30)
+            .critical(95)
+            .default_percent(70)
+            .config();
+
+        let toml: Config = toml::from_str(
+            r#"
+            name = "cpu"
+            interval = 500
+            src_type = "program"
+            src_path = "/usr/bin/gpu-usage"
+            src_args = [ "temp" ]
+            valid_max = 120000
+            dests = [
+                { path = "/sys/class/hwmon/hwmon0/pwm1", dest_min = 0, dest_max = 255 },
+                { path = "/sys/class/hwmon/hwmon0/pwm2", dest_min = 60, dest_max = 200, scale = 0.5 },
+            ]
+            points = [ [30, 20], [80, 100] ]
+            curve = "step"
+            min_start_percent = 40.0
+            min_run_percent = 25.0
+            stop_below = 30
+            critical = 95
+            default_dest_percent = 70
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config, toml);
+    }
+
+    #[test]
+    fn combines_several_sources() {
+        let sources = vec![SourceConfig {
+            path: String::from("/sys/class/hwmon/hwmon1/temp1_input"),
+            src_type: String::from("file"),
+            args: None,
+            scale: Some(0.001),
+            offset: None,
+            weight: None,
+            checks: Checks::default(),
+        }];
+
+        let config = ControlBuilder::new("case")
+            .sources(sources.clone(), "avg")
+            .config();
+
+        assert_eq!(config.sources, Some(sources));
+        assert_eq!(config.aggregate.as_deref(), Some("avg"));
+        assert_eq!(config.interval, 1000);
+    }
+
+    #[test]
+    fn refuses_to_build_a_control_without_a_source() {
+        let result = ControlBuilder::new("cpu")
+            .dest("/sys/class/hwmon/hwmon0/pwm1", 0, 255)
+            .points(&[(30, 20), (80, 100)])
+            .build(&Context::default());
+
+        assert_eq!(
+            result.err().map(|err| err.code()),
+            Some(Code::SourceTypeIsRequired)
+        );
+    }
+}
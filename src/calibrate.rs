@@ -0,0 +1,395 @@
+use std::{
+    fs,
+    sync::atomic::{AtomicBool, Ordering},
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+use crate::destination::{DestConfig, Destination};
+use crate::error::{Code, Error};
+use crate::source::{FileSource, Source};
+
+pub struct Options {
+    pub pwm_path: String,
+    pub rpm_path: String,
+    // in raw pwm units
+    pub step: i64,
+    pub pwm_max: i64,
+    // how often the rpm is read while waiting for it to settle
+    pub settle_interval: Duration,
+    // give up waiting for the rpm to settle after this long
+    pub settle_timeout: Duration,
+    pub snippet: bool,
+}
+
+impl Options {
+    pub fn new(pwm_path: String, rpm_path: String) -> Self {
+        Self {
+            pwm_path,
+            rpm_path,
+            step: 15,
+            pwm_max: 255,
+            settle_interval: Duration::from_millis(1000),
+            settle_timeout: Duration::from_secs(10),
+            snippet: false,
+        }
+    }
+
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        if args.len() < 2 {
+            return Err(String::from(
+                "Usage: kool calibrate <pwm_path> <rpm_path> [--step N] [--max N] [--settle ms] [--snippet]",
+            ));
+        }
+
+        let mut options = Options::new(args[0].clone(), args[1].clone());
+        let mut rest = args[2..].iter();
+
+        while let Some(arg) = rest.next() {
+            match arg.as_str() {
+                "--snippet" => options.snippet = true,
+                "--step" | "--max" | "--settle" => {
+                    let value = rest
+                        .next()
+                        .and_then(|v| v.parse::<u64>().ok())
+                        .ok_or_else(|| format!("Argument {} requires a number", arg))?;
+
+                    match arg.as_str() {
+                        "--step" => options.step = value.max(1) as i64,
+                        "--max" => options.pwm_max = value as i64,
+                        _ => options.settle_interval = Duration::from_millis(value),
+                    }
+                }
+                _ => return Err(format!("Unknown argument {}", arg)),
+            }
+        }
+
+        Ok(options)
+    }
+}
+
+pub struct Calibration {
+    // lowest duty that keeps the fan spinning when stepping down
+    pub stop_pwm: Option<i64>,
+    // lowest duty that starts the fan when stepping up from a standstill
+    pub start_pwm: Option<i64>,
+    // (pwm, rpm) measured while stepping down
+    pub table: Vec<(i64, i64)>,
+}
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_interrupt(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+// the fan is stopped on purpose during calibration, so ctrl-c has to restore it,
+// the measurement stops at the next reading and the fan is handed back on drop
+pub fn handle_interrupts() {
+    unsafe {
+        libc::signal(
+            libc::SIGINT,
+            on_interrupt as *const () as libc::sighandler_t,
+        );
+        libc::signal(
+            libc::SIGTERM,
+            on_interrupt as *const () as libc::sighandler_t,
+        );
+    }
+}
+
+fn check_interrupted() -> Result<(), Error> {
+    if INTERRUPTED.load(Ordering::SeqCst) {
+        return Err(Error::new(
+            Code::General,
+            String::from("Interrupted, the fan was restored"),
+        ));
+    }
+
+    Ok(())
+}
+
+struct Fan {
+    pwm: Destination,
+    rpm: FileSource,
+    enable_path: String,
+    saved_enable: Option<String>,
+    saved_pwm: Option<String>,
+}
+
+impl Fan {
+    fn take_over(options: &Options) -> Result<Self, Error> {
+        let rpm = FileSource::new(&options.rpm_path).ok_or_else(|| {
+            Error::new(
+                Code::General,
+                format!("Cannot open/find rpm file: {}", options.rpm_path),
+            )
+        })?;
+
+        // everything is opened before switching to manual, nothing to restore on failure
+        let pwm = Destination::new(&DestConfig {
+            path: options.pwm_path.clone(),
+            dest_min: 0,
+            dest_max: options.pwm_max,
+            scale: None,
+            offset: None,
+            rpm_path: None,
+        })?;
+
+        let enable_path = format!("{}_enable", options.pwm_path);
+        let saved_enable = fs::read_to_string(&enable_path).ok();
+        let saved_pwm = fs::read_to_string(&options.pwm_path).ok();
+
+        // 1 is manual control for hwmon pwm outputs
+        if saved_enable.is_some() && fs::write(&enable_path, "1").is_err() {
+            return Err(Error::new(
+                Code::UnableToWrite,
+                format!("Unable to switch {} to manual control", options.pwm_path),
+            ));
+        }
+
+        // restores the saved values when dropped from here on
+        Ok(Self {
+            pwm,
+            rpm,
+            enable_path,
+            saved_enable,
+            saved_pwm,
+        })
+    }
+
+    // waits until two readings in a row are within 5% of each other
+    fn settle(&mut self, options: &Options) -> Result<i64, Error> {
+        let start = Instant::now();
+        let mut last = self.rpm.get(Duration::ZERO)?;
+
+        loop {
+            sleep(options.settle_interval);
+            check_interrupted()?;
+
+            let rpm = self.rpm.get(Duration::ZERO)?;
+            let tolerance = (last.max(rpm) / 20).max(30);
+
+            if (rpm - last).abs() <= tolerance || start.elapsed() > options.settle_timeout {
+                return Ok(rpm);
+            }
+
+            last = rpm;
+        }
+    }
+
+    fn measure(&mut self, pwm: i64, options: &Options) -> Result<i64, Error> {
+        check_interrupted()?;
+        self.pwm.write_raw(pwm)?;
+        self.settle(options)
+    }
+}
+
+impl Drop for Fan {
+    fn drop(&mut self) {
+        if let Some(pwm) = &self.saved_pwm {
+            if let Ok(pwm) = pwm.trim().parse::<i64>() {
+                let _ = self.pwm.write_raw(pwm);
+            }
+        }
+
+        if let Some(enable) = &self.saved_enable {
+            let _ = fs::write(&self.enable_path, enable.trim());
+        }
+    }
+}
+
+pub fn calibrate(options: &Options) -> Result<Calibration, Error> {
+    let mut fan = Fan::take_over(options)?;
+
+    let mut calibration = Calibration {
+        stop_pwm: None,
+        start_pwm: None,
+        table: Vec::new(),
+    };
+
+    // step down from full speed until the fan stops
+    let mut pwm = options.pwm_max;
+
+    loop {
+        let rpm = fan.measure(pwm, options)?;
+        calibration.table.push((pwm, rpm));
+
+        if rpm == 0 {
+            break;
+        }
+
+        calibration.stop_pwm = Some(pwm);
+
+        if pwm == 0 {
+            break;
+        }
+
+        pwm = (pwm - options.step).max(0);
+    }
+
+    // and back up from a standstill until it starts
+    if calibration.table.last().map(|(_, rpm)| *rpm) == Some(0) {
+        let mut pwm = 0;
+
+        while pwm <= options.pwm_max {
+            if fan.measure(pwm, options)? > 0 {
+                calibration.start_pwm = Some(pwm);
+                break;
+            }
+
+            pwm += options.step;
+        }
+    }
+
+    Ok(calibration)
+}
+
+impl Calibration {
+    pub fn report(&self, options: &Options) -> String {
+        let mut report = String::from("  pwm     rpm\n");
+
+        for (pwm, rpm) in self.table.iter() {
+            report.push_str(&format!("{:5} {:7}\n", pwm, rpm));
+        }
+
+        match self.stop_pwm {
+            Some(pwm) => report.push_str(&format!("Fan keeps spinning down to pwm {}\n", pwm)),
+            None => report.push_str("Fan didn't spin at any duty\n"),
+        }
+
+        match (self.start_pwm, self.table.last()) {
+            (Some(pwm), _) => report.push_str(&format!("Fan starts at pwm {}\n", pwm)),
+            (None, Some((_, rpm))) if *rpm > 0 => report.push_str("Fan never stops\n"),
+            _ => report.push_str("Fan didn't start\n"),
+        }
+
+        if options.snippet {
+            report.push('\n');
+            report.push_str(&self.snippet(options));
+        }
+
+        report
+    }
+
+    pub fn snippet(&self, options: &Options) -> String {
+        let percent = |pwm: i64| (pwm * 100 + options.pwm_max - 1) / options.pwm_max.max(1);
+
+        let mut snippet = format!(
+            "dest_path = \"{}\"\nrpm_path = \"{}\"\ndest_min = 0\ndest_max = {}\n",
+            options.pwm_path, options.rpm_path, options.pwm_max
+        );
+
+        if let Some(pwm) = self.stop_pwm {
+            snippet.push_str(&format!("min_run_percent = {}\n", percent(pwm)));
+        }
+
+        if let Some(pwm) = self.start_pwm {
+            snippet.push_str(&format!("min_start_percent = {}\n", percent(pwm)));
+        }
+
+        snippet
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        fs::OpenOptions,
+        io::Write,
+        path::{Path, PathBuf},
+        process,
+        sync::{atomic::AtomicBool, Arc},
+        thread,
+    };
+
+    // pwm1, pwm1_enable and fan1_input in a directory of their own
+    fn fake_hwmon(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("kool-calibrate-{}-{}", process::id(), name));
+
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        fs::write(dir.join("pwm1"), "128").unwrap();
+        fs::write(dir.join("pwm1_enable"), "2").unwrap();
+        fs::write(dir.join("fan1_input"), "1280").unwrap();
+
+        dir
+    }
+
+    // spins at 10 rpm per pwm, stops below 40 and only starts again from 80
+    fn simulate_fan(dir: PathBuf, running: Arc<AtomicBool>) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            let mut spinning = true;
+
+            while running.load(Ordering::SeqCst) {
+                if let Some(pwm) = fs::read_to_string(dir.join("pwm1"))
+                    .ok()
+                    .and_then(|pwm| pwm.trim().parse::<i64>().ok())
+                {
+                    spinning = if spinning { pwm >= 40 } else { pwm >= 80 };
+
+                    let rpm = if spinning { pwm * 10 } else { 0 };
+
+                    // in place and padded, sysfs never shows a half written value
+                    if let Ok(mut file) =
+                        OpenOptions::new().write(true).open(dir.join("fan1_input"))
+                    {
+                        let _ = write!(file, "{:<8}", rpm);
+                    }
+                }
+
+                sleep(Duration::from_millis(2));
+            }
+        })
+    }
+
+    fn options(dir: &Path) -> Options {
+        let mut options = Options::new(
+            dir.join("pwm1").to_string_lossy().to_string(),
+            dir.join("fan1_input").to_string_lossy().to_string(),
+        );
+
+        options.step = 20;
+        options.settle_interval = Duration::from_millis(20);
+        options.settle_timeout = Duration::from_millis(500);
+
+        options
+    }
+
+    #[test]
+    fn finds_stop_and_start_and_restores_the_fan() {
+        let dir = fake_hwmon("simulated");
+        let running = Arc::new(AtomicBool::new(true));
+        let fan = simulate_fan(dir.clone(), Arc::clone(&running));
+
+        let calibration = calibrate(&options(&dir));
+
+        running.store(false, Ordering::SeqCst);
+        fan.join().unwrap();
+
+        let calibration = calibration.unwrap();
+
+        assert_eq!(calibration.stop_pwm, Some(55));
+        assert_eq!(calibration.start_pwm, Some(80));
+        assert_eq!(calibration.table.first(), Some(&(255, 2550)));
+        assert_eq!(calibration.table.last(), Some(&(35, 0)));
+
+        assert_eq!(fs::read_to_string(dir.join("pwm1")).unwrap(), "128");
+        assert_eq!(fs::read_to_string(dir.join("pwm1_enable")).unwrap(), "2");
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn leaves_the_enable_mode_alone_when_the_pwm_cannot_be_opened() {
+        let dir = fake_hwmon("missing");
+        fs::remove_file(dir.join("pwm1")).unwrap();
+
+        assert!(calibrate(&options(&dir)).is_err());
+        assert_eq!(fs::read_to_string(dir.join("pwm1_enable")).unwrap(), "2");
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,410 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    os::unix::net::UnixStream,
+    time::{Duration, SystemTime},
+};
+
+use crate::control::Status;
+use crate::error::{Code, Error};
+use crate::logger::rfc3339;
+use crate::socket::{Request, Response};
+
+pub enum Command {
+    Status,
+    Set {
+        control: String,
+        percent: f64,
+        duration: Option<Duration>,
+    },
+    Release {
+        control: String,
+    },
+    Watch {
+        interval: Duration,
+    },
+    Profile {
+        name: String,
+    },
+}
+
+pub struct Options {
+    pub socket: String,
+    pub command: Command,
+}
+
+// 90, 90s, 10m, 1h
+fn parse_duration(value: &str) -> Option<Duration> {
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(idx) => value.split_at(idx),
+        None => (value, "s"),
+    };
+
+    let number = number.parse::<u64>().ok()?;
+
+    let secs = match unit {
+        "s" => Some(number),
+        "m" => number.checked_mul(60),
+        "h" => number.checked_mul(3600),
+        _ => None,
+    };
+
+    secs.map(Duration::from_secs)
+}
+
+impl Options {
+    const USAGE: &'static str = "Usage: kool status | set <control> <percent> [--for 10m] | release <control> | watch [--interval ms] | profile <name>, all take [--socket path]";
+
+    pub fn parse(command: &str, args: &[String]) -> Result<Self, String> {
+        let mut socket = String::from("/run/kool.sock");
+        let mut duration = None;
+        let mut interval = Duration::from_millis(1000);
+        let mut positional = Vec::new();
+        let mut rest = args.iter();
+
+        while let Some(arg) = rest.next() {
+            match arg.as_str() {
+                "--socket" => {
+                    socket = rest
+                        .next()
+                        .cloned()
+                        .ok_or_else(|| String::from("Argument --socket requires a path"))?;
+                }
+                "--for" => {
+                    duration =
+                        Some(rest.next().and_then(|v| parse_duration(v)).ok_or_else(|| {
+                            String::from("Argument --for requires a duration like 90s or 10m")
+                        })?);
+                }
+                "--interval" => {
+                    interval = rest
+                        .next()
+                        .and_then(|v| v.parse::<u64>().ok())
+                        .map(|v| Duration::from_millis(v.max(100)))
+                        .ok_or_else(|| String::from("Argument --interval requires a number"))?;
+                }
+                _ if arg.starts_with("--") => return Err(format!("Unknown argument {}", arg)),
+                _ => positional.push(arg.clone()),
+            }
+        }
+
+        let command = match (command, positional.as_slice()) {
+            ("status", []) => Command::Status,
+            ("watch", []) => Command::Watch { interval },
+            ("release", [control]) => Command::Release {
+                control: control.clone(),
+            },
+            ("profile", [name]) => Command::Profile { name: name.clone() },
+            ("set", [control, percent]) => Command::Set {
+                control: control.clone(),
+                percent: percent
+                    .trim_end_matches('%')
+                    .parse::<f64>()
+                    .map_err(|_| format!("Invalid percent {}", percent))?,
+                duration,
+            },
+            _ => return Err(String::from(Options::USAGE)),
+        };
+
+        Ok(Self { socket, command })
+    }
+}
+
+pub struct Client {
+    stream: UnixStream,
+    reader: BufReader<UnixStream>,
+}
+
+impl Client {
+    pub fn connect(path: &str) -> Result<Self, Error> {
+        let error = |err: std::io::Error| {
+            Error::new(
+                Code::InvalidSocketConfig,
+                format!(
+                    "Cannot connect to {}: {}, is kool running with socket set?",
+                    path, err
+                ),
+            )
+        };
+
+        let stream = UnixStream::connect(path).map_err(error)?;
+        let reader = BufReader::new(stream.try_clone().map_err(error)?);
+
+        Ok(Self { stream, reader })
+    }
+
+    pub fn request(&mut self, request: &Request) -> Result<Response, Error> {
+        self.send(request)?;
+        self.receive()
+    }
+
+    fn send(&mut self, request: &Request) -> Result<(), Error> {
+        let line = serde_json::to_string(request)
+            .map_err(|err| Error::new(Code::InvalidRequest, err.to_string()))?;
+
+        writeln!(self.stream, "{}", line)
+            .map_err(|err| Error::new(Code::InvalidRequest, err.to_string()))
+    }
+
+    // the next response, a watch keeps sending them
+    fn receive(&mut self) -> Result<Response, Error> {
+        let failed = |message: String| Error::new(Code::InvalidRequest, message);
+
+        let mut line = String::new();
+        let read = self
+            .reader
+            .read_line(&mut line)
+            .map_err(|err| failed(err.to_string()))?;
+
+        if read == 0 {
+            return Err(failed(String::from("Connection closed by kool")));
+        }
+
+        let response: Response = serde_json::from_str(&line)
+            .map_err(|err| failed(format!("Invalid response: {}", err)))?;
+
+        match response.error {
+            Some(error) if !response.ok => Err(failed(error)),
+            _ => Ok(response),
+        }
+    }
+}
+
+fn state(status: &Status) -> String {
+    match status.manual_remaining {
+        Some(secs) if status.state == "manual" => {
+            format!("manual {}m{:02}s", secs / 60, secs % 60)
+        }
+        _ => status.state.clone(),
+    }
+}
+
+fn rpm(status: &Status) -> String {
+    let rpms = status
+        .outputs
+        .iter()
+        .filter_map(|output| output.rpm.map(|rpm| rpm.to_string()))
+        .collect::<Vec<_>>();
+
+    if rpms.is_empty() {
+        String::from("-")
+    } else {
+        rpms.join("/")
+    }
+}
+
+fn print_status(response: Response) {
+    if let Some(profile) = response.profile {
+        println!("Profile: {}", profile);
+    }
+
+    print!("{}", table(&response.controls.unwrap_or_default()));
+}
+
+pub fn table(controls: &[Status]) -> String {
+    let width = controls
+        .iter()
+        .map(|status| status.name.len())
+        .max()
+        .unwrap_or(0)
+        .max(7);
+
+    let mut table = format!(
+        "{:width$}  {:14}  {:>6}  {:>6}  {}\n",
+        "CONTROL",
+        "STATE",
+        "TEMP",
+        "DUTY",
+        "RPM",
+        width = width
+    );
+
+    for status in controls {
+        table.push_str(&format!(
+            "{:width$}  {:14}  {:>6}  {:>5.1}%  {}\n",
+            status.name,
+            state(status),
+            status
+                .reading
+                .map(|reading| reading.to_string())
+                .unwrap_or(String::from("-")),
+            status.target,
+            rpm(status),
+            width = width
+        ));
+    }
+
+    table
+}
+
+pub fn run(options: &Options) -> Result<(), Error> {
+    let mut client = Client::connect(&options.socket)?;
+
+    match &options.command {
+        Command::Status => {
+            print_status(client.request(&Request::Status)?);
+        }
+        Command::Set {
+            control,
+            percent,
+            duration,
+        } => {
+            let response = client.request(&Request::Override {
+                control: control.clone(),
+                percent: *percent,
+                duration: duration.map(|duration| duration.as_secs()),
+            })?;
+            println!("{}", response.message.unwrap_or_default());
+        }
+        Command::Release { control } => {
+            let response = client.request(&Request::Release {
+                control: control.clone(),
+            })?;
+            println!("{}", response.message.unwrap_or_default());
+        }
+        Command::Profile { name } => {
+            let response = client.request(&Request::Profile { name: name.clone() })?;
+            println!("{}", response.message.unwrap_or_default());
+        }
+        Command::Watch { interval } => {
+            client.send(&Request::Watch {
+                interval: Some(interval.as_millis() as u64),
+            })?;
+
+            loop {
+                let response = client.receive()?;
+
+                println!("{}", rfc3339(SystemTime::now()));
+                print_status(response);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::OutputStatus;
+
+    fn status(name: &str, state: &str) -> Status {
+        Status {
+            name: name.to_string(),
+            state: state.to_string(),
+            profile: None,
+            reading: Some(45000),
+            raw: None,
+            target: 40.0,
+            manual: None,
+            manual_remaining: None,
+            outputs: Vec::new(),
+        }
+    }
+
+    fn output(rpm: Option<i64>) -> OutputStatus {
+        OutputStatus {
+            dest: String::from("/sys/class/hwmon/hwmon0/pwm1"),
+            percent: 40.0,
+            pwm: Some(102),
+            rpm,
+        }
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("90"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("90s"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("10m"), Some(Duration::from_secs(600)));
+        assert_eq!(parse_duration("2h"), Some(Duration::from_secs(7200)));
+        assert_eq!(parse_duration("0m"), Some(Duration::ZERO));
+
+        assert_eq!(parse_duration(""), None);
+        assert_eq!(parse_duration("m"), None);
+        assert_eq!(parse_duration("10d"), None);
+        assert_eq!(parse_duration("-5s"), None);
+        assert_eq!(parse_duration("1.5h"), None);
+        assert_eq!(parse_duration("10 m"), None);
+    }
+
+    #[test]
+    fn refuses_durations_that_overflow() {
+        let max = u64::MAX.to_string();
+
+        assert_eq!(parse_duration(&max), Some(Duration::from_secs(u64::MAX)));
+        assert_eq!(parse_duration(&format!("{}m", max)), None);
+        assert_eq!(parse_duration(&format!("{}h", u64::MAX / 3600 + 1)), None);
+        assert_eq!(parse_duration("99999999999999999999s"), None);
+    }
+
+    #[test]
+    fn parses_the_arguments() {
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+
+        let options = Options::parse("set", &args(&["gpu", "80%", "--for", "10m"])).unwrap();
+        match options.command {
+            Command::Set {
+                control,
+                percent,
+                duration,
+            } => {
+                assert_eq!(control, "gpu");
+                assert_eq!(percent, 80.0);
+                assert_eq!(duration, Some(Duration::from_secs(600)));
+            }
+            _ => panic!("expected set"),
+        }
+        assert_eq!(options.socket, "/run/kool.sock");
+
+        let options = Options::parse("watch", &args(&["--interval", "10", "--socket", "/tmp/k"]));
+        match options.unwrap() {
+            Options {
+                socket,
+                command: Command::Watch { interval },
+            } => {
+                assert_eq!(socket, "/tmp/k");
+                assert_eq!(interval, Duration::from_millis(100));
+            }
+            _ => panic!("expected watch"),
+        }
+
+        assert!(Options::parse("set", &args(&["gpu", "80", "--for", "1y"])).is_err());
+        assert!(Options::parse("set", &args(&["gpu", "fast"])).is_err());
+        assert!(Options::parse("status", &args(&["--verbose"])).is_err());
+        assert!(Options::parse("release", &args(&[])).is_err());
+    }
+
+    #[test]
+    fn formats_the_table() {
+        let mut manual = status("gpu_control", "manual");
+        manual.manual = Some(80.0);
+        manual.manual_remaining = Some(125);
+        manual.target = 80.0;
+        manual.outputs = vec![output(Some(900)), output(None), output(Some(1200))];
+
+        let mut failing = status("cpu", "failsafe");
+        failing.reading = None;
+        failing.outputs = vec![output(None)];
+
+        let held = Status {
+            raw: Some(90000),
+            ..status("case", "normal")
+        };
+
+        assert_eq!(
+            table(&[manual, failing, held]),
+            "CONTROL      STATE             TEMP    DUTY  RPM\n\
+             gpu_control  manual 2m05s     45000   80.0%  900/1200\n\
+             cpu          failsafe             -   40.0%  -\n\
+             case         normal           45000   40.0%  -\n"
+        );
+    }
+
+    #[test]
+    fn keeps_the_header_for_short_names() {
+        assert_eq!(
+            table(&[status("a", "normal")]),
+            "CONTROL  STATE             TEMP    DUTY  RPM\n\
+             a        normal           45000   40.0%  -\n"
+        );
+    }
+}
@@ -1,183 +1,1206 @@
 use serde::{Deserialize, Serialize};
 use std::{
-    fs::{self, OpenOptions},
-    io::Write,
-    time::Duration,
+    fmt, fs,
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
+use crate::destination::{self, DestConfig, Destination};
+use crate::emergency::Emergency;
 use crate::error::{Code, Error};
+use crate::event::{Event, Payload, Severity};
+use crate::profile::{CurveConfig, Profiles};
+use crate::schedule::{self, Caps, SystemClock};
+use crate::signal::Emitter;
 use crate::source;
 
-#[derive(Debug, Serialize, Deserialize, Hash, Eq, PartialEq, Clone)]
+/// A `[[control]]` of the config file, see [`crate::ControlBuilder`] to build one in code.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Default)]
 pub struct Config {
     pub name: String,
     pub interval: u64,
-    src_path: String,
-    src_type: String,
-    src_args: Option<Vec<String>>,
-    dest_path: String,
-    dest_min: i64,
-    dest_max: i64,
-    default_dest_percent: Option<i32>,
-    curve: Option<String>,
+    pub(crate) src_path: Option<String>,
+    pub(crate) src_type: Option<String>,
+    pub(crate) src_args: Option<Vec<String>>,
+    // for src_type expr, e.g. max(cpu, gpu - 5) over the shared sources
+    pub(crate) expr: Option<String>,
+    // stale reading checks of the source
+    #[serde(flatten)]
+    pub(crate) checks: source::Checks,
+    // multiple sources combined into one reading, used instead of src_path
+    pub(crate) sources: Option<Vec<source::SourceConfig>>,
+    pub(crate) aggregate: Option<String>,
+    // skip or fail when one of the aggregated sources fails
+    pub(crate) on_source_error: Option<String>,
+    // for src_type difference, the reading is minuend - subtrahend
+    pub(crate) minuend: Option<source::SourceConfig>,
+    pub(crate) subtrahend: Option<source::SourceConfig>,
+    pub(crate) dest_path: Option<String>,
+    pub(crate) dest_min: Option<i64>,
+    pub(crate) dest_max: Option<i64>,
+    pub(crate) rpm_path: Option<String>,
+    // multiple outputs following the same curve, used instead of dest_path
+    pub(crate) dests: Option<Vec<DestConfig>>,
+    // a fan is stalled when it reports 0 rpm while the duty is above stall_percent
+    // for stall_cycles in a row
+    pub(crate) stall_percent: Option<f64>,
+    pub(crate) stall_cycles: Option<u32>,
+    // drive the rest of the group to maximum while a fan is stalled
+    pub(crate) stall_boost: Option<bool>,
+    // fans that won't start below min_start_percent but keep spinning at min_run_percent
+    pub(crate) min_start_percent: Option<f64>,
+    pub(crate) min_run_percent: Option<f64>,
+    // source temperature below which the fans are stopped
+    pub(crate) stop_below: Option<i64>,
+    // duty held for spinup_ms when a fan starts from zero
+    pub(crate) spinup_kick_percent: Option<f64>,
+    pub(crate) spinup_ms: Option<u64>,
+    // source temperature at which every control goes to maximum
+    pub(crate) critical: Option<i64>,
+    // whether this control follows the emergency override, defaults to true
+    pub(crate) emergency: Option<bool>,
+    // in milliseconds, unchanged values are only rewritten this often, defaults to 10s
+    pub(crate) refresh_interval: Option<u64>,
+    pub(crate) default_dest_percent: Option<i32>,
+    // linear or step, defaults to linear
+    pub(crate) curve: Option<String>,
+    pub(crate) points: Vec<Vec<i64>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CurveKind {
+    // interpolates between the points
+    Linear,
+    // holds the value of the last point below the input
+    Step,
+}
+
+// the curve of a control with the active profile applied
+pub struct Curve {
+    kind: CurveKind,
     points: Vec<Vec<i64>>,
+    min_start_percent: Option<f64>,
+    min_run_percent: Option<f64>,
+    stop_below: Option<i64>,
+}
+
+impl Curve {
+    pub fn new(config: &Config, profile: Option<&CurveConfig>) -> Result<Self, Error> {
+        let kind = profile
+            .and_then(|p| p.curve.as_ref())
+            .or(config.curve.as_ref())
+            .map(|kind| kind.as_str())
+            .unwrap_or("linear");
+
+        let kind = match kind {
+            "linear" => CurveKind::Linear,
+            "step" => CurveKind::Step,
+            kind => {
+                return Err(Error::new(
+                    Code::InvalidConfigCurvePoints,
+                    format!("Unknown curve {} for control {}", kind, config.name),
+                ))
+            }
+        };
+
+        let points = profile
+            .and_then(|p| p.points.clone())
+            .unwrap_or(config.points.clone());
+
+        if points.is_empty() || points.iter().any(|p| p.len() < 2) {
+            return Err(Error::new(
+                Code::InvalidConfigCurvePoints,
+                format!(
+                    "Invalid curve graph points for config name: {}",
+                    config.name
+                ),
+            ));
+        }
+
+        Ok(Self {
+            kind,
+            points,
+            min_start_percent: profile
+                .and_then(|p| p.min_start_percent)
+                .or(config.min_start_percent),
+            min_run_percent: profile
+                .and_then(|p| p.min_run_percent)
+                .or(config.min_run_percent),
+            stop_below: profile.and_then(|p| p.stop_below).or(config.stop_below),
+        })
+    }
+
+    // only stop_below or min_start_percent turn a 0% into a stopped fan,
+    // otherwise 0% is dest_min like the rest of the curve
+    fn stops(&self) -> bool {
+        self.stop_below.is_some() || self.min_start_percent.is_some()
+    }
+
+    // maps the input through the curve points, output is in percent
+    fn percent(&self, input: i64) -> f64 {
+        let points = &self.points;
+
+        let upper_idx = points.iter().position(|v| v[0] >= input);
+
+        match (upper_idx, self.kind) {
+            // take care of lower values
+            (Some(0), _) => points[0][1] as f64,
+            (Some(uidx), CurveKind::Linear) => {
+                let lower_point = &points[uidx - 1];
+                let upper_point = &points[uidx];
+
+                destination::lerp(
+                    lower_point[0] as f64,
+                    upper_point[0] as f64,
+                    input as f64,
+                    lower_point[1] as f64,
+                    upper_point[1] as f64,
+                )
+            }
+            (Some(uidx), CurveKind::Step) if points[uidx][0] == input => points[uidx][1] as f64,
+            (Some(uidx), CurveKind::Step) => points[uidx - 1][1] as f64,
+            // and higher values
+            (None, _) => points[points.len() - 1][1] as f64,
+        }
+    }
+}
+
+// start/stop handling of the fans, applied after the curve
+struct OutputStage {
+    running: bool,
+    // written as a raw 0 instead of dest_min
+    stopped: bool,
+    kick_until: Option<Instant>,
+}
+
+impl OutputStage {
+    fn new() -> Self {
+        // the state of the fans is unknown on start, assume they are stopped
+        Self {
+            running: false,
+            stopped: false,
+            kick_until: None,
+        }
+    }
+
+    // manual, failsafe and emergency duty skip the start/stop handling
+    fn hold(&mut self, percent: f64) {
+        self.running = percent > 0.0;
+        self.stopped = false;
+    }
+
+    fn apply(&mut self, config: &Config, curve: &Curve, input: i64, percent: f64) -> f64 {
+        let stop = match curve.stop_below {
+            Some(stop_below) => input < stop_below,
+            None => false,
+        };
+
+        if stop || percent <= 0.0 {
+            self.running = false;
+            self.stopped = curve.stops();
+            self.kick_until = None;
+            return 0.0;
+        }
+
+        self.stopped = false;
+
+        let mut percent = percent;
+
+        if self.running {
+            percent = percent.max(curve.min_run_percent.unwrap_or(0.0));
+        } else {
+            self.running = true;
+            percent = percent.max(curve.min_start_percent.unwrap_or(0.0));
+
+            if config.spinup_kick_percent.is_some() {
+                self.kick_until =
+                    Some(Instant::now() + Duration::from_millis(config.spinup_ms.unwrap_or(1000)));
+            }
+        }
+
+        if let Some(kick_until) = self.kick_until {
+            if Instant::now() < kick_until {
+                percent = percent.max(config.spinup_kick_percent.unwrap_or(100.0));
+            } else {
+                self.kick_until = None;
+            }
+        }
+
+        percent
+    }
+}
+
+/// State shared by all the controls.
+pub struct Context {
+    /// Sources polled once and read by any control, by name.
+    pub shared: source::Registry,
+    /// Drives every control to maximum once one of them crosses `critical`.
+    pub emergency: Arc<Emergency>,
+    /// Curves replacing the ones of the controls.
+    pub profiles: Arc<Profiles>,
+    /// Duty limits of the schedules.
+    pub caps: Arc<Caps>,
+}
+
+// no shared sources, profiles or schedules and no critical command
+impl Default for Context {
+    fn default() -> Self {
+        Self {
+            shared: source::Registry::new(),
+            emergency: Arc::new(Emergency::new(None, None)),
+            profiles: Arc::new(Profiles::new(Vec::new(), &[], None).unwrap()),
+            caps: Arc::new(schedule::create(&[], Arc::new(SystemClock {})).unwrap().1),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[non_exhaustive]
+pub enum State {
+    Normal,
+    // the source is failing, running at default_dest_percent
+    Failsafe,
+    Emergency,
+    // held at a percent set over the control socket
+    Manual,
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            State::Normal => "normal",
+            State::Failsafe => "failsafe",
+            State::Emergency => "emergency",
+            State::Manual => "manual",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+// a percent set by hand, released after until
+struct Manual {
+    percent: f64,
+    until: Option<Instant>,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct OutputStatus {
+    pub dest: String,
+    pub percent: f64,
+    pub pwm: Option<i64>,
+    pub rpm: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Status {
+    pub name: String,
+    pub state: String,
+    pub profile: Option<String>,
+    // after the checks, what the curve got
+    pub reading: Option<i64>,
+    // before the checks, when it differs from the reading
+    pub raw: Option<i64>,
+    pub target: f64,
+    pub manual: Option<f64>,
+    // in seconds, none when the override holds until released
+    pub manual_remaining: Option<u64>,
+    pub outputs: Vec<OutputStatus>,
+}
+
+/// Reads a source, maps the reading through a curve and writes it to the outputs.
 pub struct Control {
     config: Config,
+    curve: Curve,
+    profiles: Arc<Profiles>,
+    caps: Arc<Caps>,
+    // profile the curve was built with
+    profile: Option<String>,
     source: Box<dyn source::Source>,
-    // for now only file is supported
-    dest: fs::File,
+    dests: Vec<Destination>,
+    // a fan of the group is stalled, with stall_boost the group runs at maximum
+    stalled: bool,
+    output: OutputStage,
+    emergency: Arc<Emergency>,
+    state: State,
+    // output of the control in percent, before the destinations scale it
+    target: f64,
+    reading: Option<i64>,
+    raw: Option<i64>,
+    manual: Option<Manual>,
+    // collected during a tick and emitted at the end of it
+    events: Vec<Event>,
     interval: Duration,
 }
 
-unsafe impl Send for Control {}
-unsafe impl Sync for Control {}
-
 impl Control {
-    pub fn new(config: Config) -> Result<Self, Error> {
-        let source: Box<dyn source::Source>;
+    /// Opens the source and the destinations described by `config`.
+    pub fn new(config: Config, context: &Context) -> Result<Self, Error> {
+        let source = Control::create_source(&config, &context.shared)?;
+        let source = config.checks.apply(
+            source,
+            config.src_type.as_deref().unwrap_or_default(),
+            config.src_path.as_deref().unwrap_or(&config.name),
+        )?;
 
-        let interval = Duration::from_millis(config.interval);
+        Control::with_source(config, source, context)
+    }
 
-        match config.src_type.to_lowercase().as_str() {
-            "file" => {
-                if let Some(src) = source::FileSource::new(&config.src_path) {
-                    source = Box::new(src);
-                } else {
+    /// Checks `config` like [`Control::new`] without opening the destinations.
+    pub fn check(config: &Config, context: &Context) -> Result<(), Error> {
+        let source = Control::create_source(config, &context.shared)?;
+        config.checks.apply(
+            source,
+            config.src_type.as_deref().unwrap_or_default(),
+            config.src_path.as_deref().unwrap_or(&config.name),
+        )?;
+
+        Curve::new(config, context.profiles.active().get(&config.name))?;
+
+        // only looked up, opening them for writing needs root
+        for dest in Control::dest_configs(config)? {
+            let paths = std::iter::once(&dest.path).chain(dest.rpm_path.as_ref());
+
+            for path in paths {
+                if let Err(err) = fs::metadata(path) {
                     return Err(Error::new(
-                        Code::General,
-                        format!("Cannot open/find file: {}", config.src_path),
+                        Code::CannotOpenDestinationFile,
+                        format!("Cannot find destination {}: {}", path, err),
                     ));
                 }
             }
-            "program" => {
-                source = Box::new(source::ProgramSource::new(
-                    &config.src_path,
-                    config.src_args.as_ref(),
-                ));
-            }
-            _ => {
-                return Err(Error::new(
-                    Code::SourceTypeIsRequired,
-                    format!("Source type is required for contorl {}", config.name),
-                ))
-            }
         }
 
-        let dest = OpenOptions::new().write(true).open(&config.dest_path);
+        Ok(())
+    }
 
-        if let Err(_) = dest {
-            return Err(Error::new(
-                Code::CannotOpenDestinationFile,
-                format!("Cannot open destination for config name: {}", config.name),
-            ));
+    /// Like [`Control::new`] but reads `source`, the source options of `config` are ignored.
+    pub fn with_source(
+        config: Config,
+        source: Box<dyn source::Source>,
+        context: &Context,
+    ) -> Result<Self, Error> {
+        let interval = Duration::from_millis(config.interval);
+
+        let active = context.profiles.active();
+        let curve = Curve::new(&config, active.get(&config.name))?;
+
+        let dest_configs = Control::dest_configs(&config)?;
+
+        let mut dests = dest_configs
+            .iter()
+            .map(Destination::new)
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        if let Some(refresh_interval) = config.refresh_interval {
+            for dest in dests.iter_mut() {
+                dest.set_refresh_interval(Duration::from_millis(refresh_interval));
+            }
+        }
+
+        if config.emergency.unwrap_or(true) {
+            context.emergency.register(&dest_configs);
         }
 
         Ok(Self {
             config,
+            curve,
+            profiles: Arc::clone(&context.profiles),
+            caps: Arc::clone(&context.caps),
+            profile: active.name.clone(),
             source,
-            dest: dest.unwrap(),
+            dests,
+            stalled: false,
+            output: OutputStage::new(),
+            emergency: Arc::clone(&context.emergency),
+            state: State::Normal,
+            target: 0.0,
+            reading: None,
+            raw: None,
+            manual: None,
+            events: Vec::new(),
             interval,
         })
     }
 
+    fn dest_configs(config: &Config) -> Result<Vec<DestConfig>, Error> {
+        let configs = match (&config.dests, &config.dest_path) {
+            (Some(dests), _) => dests.clone(),
+            (None, Some(dest_path)) => vec![DestConfig {
+                path: dest_path.clone(),
+                dest_min: config.dest_min.unwrap_or(0),
+                dest_max: config.dest_max.unwrap_or(255),
+                scale: None,
+                offset: None,
+                rpm_path: config.rpm_path.clone(),
+            }],
+            (None, None) => {
+                return Err(Error::new(
+                    Code::CannotOpenDestinationFile,
+                    format!("Destination is required for config name: {}", config.name),
+                ))
+            }
+        };
+
+        Ok(configs)
+    }
+
+    fn create_source(
+        config: &Config,
+        shared: &source::Registry,
+    ) -> Result<Box<dyn source::Source>, Error> {
+        if let Some(sources) = &config.sources {
+            let aggregate = config.aggregate.as_deref().unwrap_or("max");
+            let aggregate = source::Aggregate::parse(aggregate).ok_or_else(|| {
+                Error::new(
+                    Code::InvalidSourceConfig,
+                    format!(
+                        "Unknown aggregate {} for control {}",
+                        aggregate, config.name
+                    ),
+                )
+            })?;
+
+            let policy = config.on_source_error.as_deref().unwrap_or("skip");
+            let policy = source::FailurePolicy::parse(policy).ok_or_else(|| {
+                Error::new(
+                    Code::InvalidSourceConfig,
+                    format!(
+                        "Unknown source error policy {} for control {}",
+                        policy, config.name
+                    ),
+                )
+            })?;
+
+            return Ok(Box::new(source::AggregateSource::from_configs(
+                sources, aggregate, policy, shared,
+            )?));
+        }
+
+        if config.src_type.as_deref() == Some("difference") {
+            return match (&config.minuend, &config.subtrahend) {
+                (Some(minuend), Some(subtrahend)) => Ok(Box::new(source::DifferenceSource::new(
+                    minuend.create(shared)?,
+                    subtrahend.create(shared)?,
+                ))),
+                _ => Err(Error::new(
+                    Code::InvalidSourceConfig,
+                    format!(
+                        "Difference source of control {} needs minuend and subtrahend",
+                        config.name
+                    ),
+                )),
+            };
+        }
+
+        let src_path = match config.src_type.as_deref() {
+            Some("expr") => config.expr.as_ref().or(config.src_path.as_ref()),
+            _ if config.expr.is_some() => {
+                return Err(Error::new(
+                    Code::InvalidSourceConfig,
+                    format!("expr of control {} needs src_type expr", config.name),
+                ))
+            }
+            _ => config.src_path.as_ref(),
+        };
+
+        match (&config.src_type, src_path) {
+            (Some(src_type), Some(src_path)) => {
+                source::create(src_type, src_path, config.src_args.as_ref(), shared)
+            }
+            (None, _) => Err(Error::new(
+                Code::SourceTypeIsRequired,
+                format!("Source type is required for contorl {}", config.name),
+            )),
+            (_, None) => Err(Error::new(
+                Code::InvalidSourceConfig,
+                format!("Source path is required for control {}", config.name),
+            )),
+        }
+    }
+
     pub fn get_interval(&self) -> &Duration {
         &self.interval
     }
 
-    fn lerp(in_min: i64, in_max: i64, in_current: i64, out_min: i64, out_max: i64) -> f64 {
-        let in_range = in_max - in_min;
-        let in_cur = in_current - in_min;
+    /// A snapshot of the state, the last reading and the outputs of the control.
+    pub fn status(&self) -> Status {
+        let now = Instant::now();
+
+        Status {
+            name: self.config.name.clone(),
+            state: self.state.to_string(),
+            profile: self.profile.clone(),
+            reading: self.reading,
+            raw: self.raw,
+            target: self.target,
+            manual: self.manual.as_ref().map(|manual| manual.percent),
+            manual_remaining: self
+                .manual
+                .as_ref()
+                .and_then(|manual| manual.until)
+                .map(|until| until.saturating_duration_since(now).as_secs()),
+            outputs: self
+                .dests
+                .iter()
+                .map(|dest| OutputStatus {
+                    dest: dest.path().clone(),
+                    percent: dest.percent(),
+                    pwm: dest.raw(),
+                    rpm: dest.last_rpm(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Holds the outputs at `percent`, for `duration` or until released.
+    /// The emergency override still takes precedence.
+    pub fn set_manual(&mut self, percent: f64, duration: Option<Duration>) {
+        self.manual = Some(Manual {
+            percent: percent.clamp(0.0, 100.0),
+            until: duration.map(|duration| Instant::now() + duration),
+        });
+    }
+
+    /// Ends a manual override, returns false when there was none.
+    pub fn release(&mut self) -> bool {
+        self.manual.take().is_some()
+    }
+
+    /// Applies the curve, limits and thresholds of `config` to the running control.
+    /// The sources and destinations stay open, changes to them need a restart.
+    pub fn reload(&mut self, config: Config) -> Result<(), Error> {
+        let mut current = self.config.clone();
+
+        current.stall_percent = config.stall_percent;
+        current.stall_cycles = config.stall_cycles;
+        current.stall_boost = config.stall_boost;
+        current.min_start_percent = config.min_start_percent;
+        current.min_run_percent = config.min_run_percent;
+        current.stop_below = config.stop_below;
+        current.spinup_kick_percent = config.spinup_kick_percent;
+        current.spinup_ms = config.spinup_ms;
+        current.critical = config.critical;
+        current.default_dest_percent = config.default_dest_percent;
+        current.curve = config.curve.clone();
+        current.points = config.points.clone();
+
+        let active = self.profiles.active();
+        let curve = Curve::new(&config, active.get(&config.name))?;
+
+        if current != config {
+            return Err(Error::new(
+                Code::InvalidRequest,
+                format!(
+                    "Sources, destinations or interval of control {} changed, restart to apply",
+                    config.name
+                ),
+            ));
+        }
+
+        self.config = config;
+        self.curve = curve;
+        self.profile = active.name.clone();
+
+        Ok(())
+    }
+
+    /// Whether the last tick ran normally, errors like a critical temperature or a stalled fan
+    /// are only returned by the tick they start on, the control stays unhealthy until they clear.
+    pub fn healthy(&self) -> bool {
+        matches!(self.state, State::Normal | State::Manual) && !self.stalled
+    }
+
+    /// One tick, run every interval, usually as a job of the pool.
+    pub fn control(&mut self, emitter: &Emitter) -> Result<(), Error> {
+        let result = self.tick();
 
-        let percent = in_cur as f64 / in_range as f64;
+        for e in self.events.drain(..) {
+            emitter.emit(e);
+        }
 
-        (out_max - out_min) as f64 * percent + out_min as f64
+        result
     }
 
-    pub fn control(&mut self) -> Result<(), Error> {
+    fn tick(&mut self) -> Result<(), Error> {
         let interval = self.config.interval as f64 * 0.5;
 
         let src = self.source.get(Duration::from_millis(interval as u64));
 
-        if let Ok(input) = src {
-            let lower_idx = self.config.points.iter().position(|v| v[0] >= input);
+        match src {
+            Ok(input) => {
+                self.reading = Some(input);
+                self.raw = self.source.raw();
 
-            // take care of lower values
-            if let Some(lidx) = lower_idx {
-                if lidx >= (self.config.points.len() - 1) {
-                    let point = self.config.points.get(lidx).unwrap();
-                    self.write_pwm_raw(point[1])
+                self.events.push(Event::new(
+                    Severity::Info,
+                    Payload::Reading {
+                        raw: self.source.raw().unwrap_or(input),
+                        value: input,
+                        label: self.source.label(),
+                    },
+                ));
+
+                let critical = self.check_critical(input);
+
+                if self.follows_emergency() {
+                    self.write_emergency();
+                    return critical;
+                }
+
+                let manual = self.manual_percent();
+
+                self.set_state(if manual.is_some() {
+                    State::Manual
                 } else {
-                    let lower_point = self.config.points.get(lidx).unwrap();
-                    let upper_point = self.config.points.get(lidx + 1).unwrap();
-
-                    self.write_pwm(
-                        lower_point[0],
-                        upper_point[0],
-                        input,
-                        lower_point[1],
-                        upper_point[1],
-                    )
+                    State::Normal
+                });
+
+                // a control staying out of the emergency keeps following its curve,
+                // the critical reading is still reported
+                if let Some(percent) = manual {
+                    self.output.hold(percent);
+                    self.target = percent;
+
+                    let written = self.write_percent(percent);
+                    return critical.and(written);
+                }
+
+                self.refresh_curve();
+
+                let percent = self.curve.percent(input);
+                let percent = self.output.apply(&self.config, &self.curve, input, percent);
+
+                // quiet hours of the schedules
+                let percent = match self.caps.max_percent(&self.config.name) {
+                    Some(max_percent) => percent.min(max_percent),
+                    None => percent,
+                };
+                self.target = percent;
+
+                let written = self.write_percent(percent);
+                critical.and(written)
+            }
+            Err(err) => {
+                // a failing source doesn't take the fans out of the emergency,
+                // even when it is the source that raised it
+                if self.follows_emergency() {
+                    self.write_emergency();
+                    return Err(err);
+                }
+
+                self.set_state(State::Failsafe);
+
+                // keep the fans at a safe speed while the source is failing
+                if let Some(percent) = self.config.default_dest_percent {
+                    self.output.hold(percent as f64);
+                    self.target = percent as f64;
+                    let _ = self.write_percent(percent as f64);
                 }
+
+                Err(err)
+            }
+        }
+    }
+
+    fn follows_emergency(&self) -> bool {
+        self.emergency.active() && self.config.emergency.unwrap_or(true)
+    }
+
+    fn write_emergency(&mut self) {
+        self.set_state(State::Emergency);
+        self.output.hold(100.0);
+        self.target = 100.0;
+
+        for dest in self.dests.iter_mut() {
+            let _ = dest.write_max();
+        }
+
+        self.push_outputs();
+    }
+
+    fn manual_percent(&mut self) -> Option<f64> {
+        let until = self.manual.as_ref()?.until;
+
+        if until.is_some_and(|until| Instant::now() >= until) {
+            self.manual = None;
+            self.events.push(Event::log(format!(
+                "Manual override of control {} expired",
+                self.config.name
+            )));
+
+            return None;
+        }
+
+        self.manual.as_ref().map(|manual| manual.percent)
+    }
+
+    fn set_state(&mut self, state: State) {
+        if self.state == state {
+            return;
+        }
+
+        let severity = if state == State::Normal {
+            Severity::Info
+        } else {
+            Severity::Warn
+        };
+
+        self.events.push(Event::new(
+            severity,
+            Payload::State {
+                from: self.state.to_string(),
+                to: state.to_string(),
+            },
+        ));
+
+        self.state = state;
+    }
+
+    fn push_outputs(&mut self) {
+        for dest in self.dests.iter() {
+            if let Some(pwm) = dest.raw() {
+                self.events.push(Event::new(
+                    Severity::Info,
+                    Payload::Output {
+                        dest: dest.path().clone(),
+                        target: self.target,
+                        percent: dest.percent(),
+                        pwm,
+                        rpm: dest.last_rpm(),
+                    },
+                ));
+            }
+        }
+    }
+
+    fn check_critical(&self, input: i64) -> Result<(), Error> {
+        let critical = match self.config.critical {
+            Some(critical) => critical,
+            None => return Ok(()),
+        };
+
+        if input < critical {
+            self.emergency.clear(&self.config.name);
+            return Ok(());
+        }
+
+        if self.emergency.raise(&self.config.name) {
+            Err(Error::new(
+                Code::CriticalTemperature,
+                format!(
+                    "Critical temperature {} reached for control {}, all fans at maximum",
+                    input, self.config.name
+                ),
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    // picks up a profile switch, the sources and destinations are left alone
+    fn refresh_curve(&mut self) {
+        let active = self.profiles.active();
+
+        if active.name == self.profile {
+            return;
+        }
+
+        if let Ok(curve) = Curve::new(&self.config, active.get(&self.config.name)) {
+            self.curve = curve;
+        }
+
+        self.profile = active.name.clone();
+    }
+
+    // all outputs are written from the same evaluation, the first error is reported
+    fn write_percent(&mut self, percent: f64) -> Result<(), Error> {
+        let mut result = Ok(());
+
+        // decided on the stall of the last tick, so the group isn't written twice a tick
+        let boost = self.stalled && self.config.stall_boost.unwrap_or(false);
+
+        for dest in self.dests.iter_mut() {
+            let written = if boost {
+                dest.write_max()
+            } else if self.output.stopped {
+                dest.write_stop()
             } else {
-                if let Some(point) = self.config.points.first() {
-                    self.write_pwm_raw(point[1])
-                } else {
-                    Err(Error::new(
-                        Code::InvalidConfigCurvePoints,
-                        format!(
-                            "Invalid curve graph points for config name: {}",
-                            &self.config.name,
-                        ),
-                    ))
+                dest.write_percent(percent).map(|_| ())
+            };
+
+            if let Err(err) = written {
+                if result.is_ok() {
+                    result = Err(err);
+                }
+            }
+
+            if let Some(value) = dest.take_overridden() {
+                self.events.push(Event::warn(format!(
+                    "Destination {} of control {} was changed to {} outside of kool",
+                    dest.path(),
+                    self.config.name,
+                    value
+                )));
+            }
+        }
+
+        let result = result.and(self.check_stall());
+
+        self.push_outputs();
+
+        result
+    }
+
+    fn check_stall(&mut self) -> Result<(), Error> {
+        let threshold = self.config.stall_percent.unwrap_or(20.0);
+        let cycles = self.config.stall_cycles.unwrap_or(3).max(1);
+
+        let mut stalled = Vec::new();
+        let mut any_stalled = false;
+        // a failing rpm file doesn't keep the rest of the group from being checked
+        let mut read_error = None;
+
+        for dest in self.dests.iter_mut() {
+            let stalled_cycles = match dest.check_stall(threshold) {
+                Ok(stalled_cycles) => stalled_cycles,
+                Err(err) => {
+                    read_error.get_or_insert(err);
+                    continue;
                 }
+            };
+
+            any_stalled |= stalled_cycles >= cycles;
+
+            // only report when the fan stalls, not on every cycle after
+            if stalled_cycles == cycles {
+                stalled.push(dest.path().clone());
             }
+        }
+
+        self.stalled = any_stalled;
+
+        if stalled.is_empty() {
+            read_error.map_or(Ok(()), Err)
         } else {
-            Err(src.err().unwrap())
-        }
-    }
-
-    fn write_pwm(
-        &mut self,
-        in_min: i64,
-        in_max: i64,
-        in_current: i64,
-        out_min: i64,
-        out_max: i64,
-    ) -> Result<(), Error> {
-        let raw_percent = Control::lerp(in_min, in_max, in_current, out_min, out_max);
-
-        let pwm = Control::lerp(
-            0,
-            100,
-            raw_percent as i64,
-            self.config.dest_min,
-            self.config.dest_max,
-        ) as i64;
-
-        match self.dest.write_all(pwm.to_string().as_bytes()) {
-            Ok(_) => {
-                let _ = self.dest.flush();
-                Ok(())
-            }
-            Err(err) => Err(Error::new(
-                Code::UnableToWrite,
-                format!("Unabe to write to destination: {}", err.to_string()),
-            )),
+            Err(Error::new(
+                Code::FanStall,
+                format!(
+                    "Fan stalled for control {}: {}",
+                    self.config.name,
+                    stalled.join(", ")
+                ),
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::ControlBuilder;
+    use crate::signal::Signal;
+    use std::{
+        path::{Path, PathBuf},
+        process,
+        sync::Mutex,
+    };
+
+    // the reading is set by the test between ticks
+    struct FakeSource {
+        reading: Arc<Mutex<Result<i64, Code>>>,
+    }
+
+    impl source::Source for FakeSource {
+        fn get(&mut self, _timeout: Duration) -> Result<i64, Error> {
+            self.reading
+                .lock()
+                .unwrap()
+                .clone()
+                .map_err(|code| Error::new(code, String::from("Sensor is gone")))
         }
     }
 
-    fn write_pwm_raw(&mut self, pwm: i64) -> Result<(), Error> {
-        match self.dest.write_all(pwm.to_string().as_bytes()) {
-            Ok(_) => {
-                let _ = self.dest.flush();
-                Ok(())
+    struct Fixture {
+        dir: PathBuf,
+        reading: Arc<Mutex<Result<i64, Code>>>,
+    }
+
+    impl Fixture {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("kool-control-{}-{}", process::id(), name));
+
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+
+            Self {
+                dir,
+                reading: Arc::new(Mutex::new(Ok(0))),
             }
-            Err(err) => Err(Error::new(
-                Code::UnableToWrite,
-                format!("Unabe to write to destination: {}", err.to_string()),
-            )),
         }
+
+        fn dest(&self, name: &str) -> String {
+            let path = self.dir.join(name);
+            fs::write(&path, "0").unwrap();
+            path.to_string_lossy().to_string()
+        }
+
+        fn source(&self) -> Box<dyn source::Source> {
+            Box::new(FakeSource {
+                reading: Arc::clone(&self.reading),
+            })
+        }
+
+        fn set(&self, reading: Result<i64, Code>) {
+            *self.reading.lock().unwrap() = reading;
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn emitter() -> Emitter {
+        Emitter::new(None, Arc::new(Mutex::new(Signal::new())))
+    }
+
+    fn pwm(path: &Path) -> String {
+        fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn writes_dest_min_at_zero_percent_without_stop_settings() {
+        let fixture = Fixture::new("dest-min");
+        let dest = fixture.dest("pwm1");
+
+        let mut control = ControlBuilder::new("case")
+            .custom_source(fixture.source())
+            .dest(&dest, 60, 255)
+            .points(&[(30000, 0), (70000, 100)])
+            .build(&Context::default())
+            .unwrap();
+
+        fixture.set(Ok(20000));
+        control.control(&emitter()).unwrap();
+
+        assert_eq!(pwm(Path::new(&dest)), "60");
+        assert_eq!(control.status().outputs[0].pwm, Some(60));
+    }
+
+    #[test]
+    fn writes_a_raw_zero_once_stop_below_stops_the_fan() {
+        let fixture = Fixture::new("stop-below");
+        let dest = fixture.dest("pwm1");
+
+        let mut control = ControlBuilder::new("case")
+            .custom_source(fixture.source())
+            .dest(&dest, 60, 255)
+            .points(&[(30000, 20), (70000, 100)])
+            .stop_below(25000)
+            .build(&Context::default())
+            .unwrap();
+
+        fixture.set(Ok(20000));
+        control.control(&emitter()).unwrap();
+        assert_eq!(pwm(Path::new(&dest)), "0");
+
+        fixture.set(Ok(30000));
+        control.control(&emitter()).unwrap();
+        assert_eq!(pwm(Path::new(&dest)), "99");
+    }
+
+    #[test]
+    fn keeps_following_the_curve_when_staying_out_of_the_emergency() {
+        let fixture = Fixture::new("critical");
+        let pump = fixture.dest("pwm1");
+        let fan = fixture.dest("pwm2");
+        let context = Context::default();
+
+        let mut config = ControlBuilder::new("pump")
+            .dest(&pump, 0, 255)
+            .points(&[(30000, 20), (100000, 60)])
+            .critical(90000)
+            .config();
+        config.emergency = Some(false);
+
+        let mut pump_control = Control::with_source(config, fixture.source(), &context).unwrap();
+
+        let mut fan_control = ControlBuilder::new("fan")
+            .custom_source(Box::new(FakeSource {
+                reading: Arc::new(Mutex::new(Ok(30000))),
+            }))
+            .dest(&fan, 0, 255)
+            .points(&[(30000, 20), (100000, 60)])
+            .build(&context)
+            .unwrap();
+
+        fixture.set(Ok(100000));
+
+        let err = pump_control.control(&emitter()).unwrap_err();
+        assert_eq!(err.code(), Code::CriticalTemperature);
+        assert_eq!(pwm(Path::new(&pump)), "153");
+        assert!(context.emergency.active());
+
+        // the rest of the controls go to maximum
+        fan_control.control(&emitter()).unwrap();
+        assert_eq!(pwm(Path::new(&fan)), "255");
+
+        pump_control.control(&emitter()).unwrap();
+        assert_eq!(pwm(Path::new(&pump)), "153");
+        assert_eq!(pump_control.status().state, "normal");
+    }
+
+    #[test]
+    fn warns_when_a_destination_was_changed_outside_of_kool() {
+        let fixture = Fixture::new("overridden");
+        let dest = fixture.dest("pwm1");
+
+        let mut config = ControlBuilder::new("case")
+            .dest(&dest, 0, 255)
+            .points(&[(30000, 20), (70000, 100)])
+            .config();
+        config.refresh_interval = Some(0);
+
+        let mut control =
+            Control::with_source(config, fixture.source(), &Context::default()).unwrap();
+
+        let warnings = Arc::new(Mutex::new(Vec::new()));
+        let mut signal = Signal::new();
+        let recorded = Arc::clone(&warnings);
+
+        signal.connect(move |e| {
+            if let Payload::Log(message) = &e.payload {
+                if e.severity == Severity::Warn {
+                    recorded.lock().unwrap().push(message.clone());
+                }
+            }
+        });
+
+        let emitter = Emitter::new(None, Arc::new(Mutex::new(signal)));
+
+        fixture.set(Ok(50000));
+        control.control(&emitter).unwrap();
+        fs::write(&dest, "255").unwrap();
+        control.control(&emitter).unwrap();
+
+        assert_eq!(pwm(Path::new(&dest)), "153");
+        assert_eq!(
+            *warnings.lock().unwrap(),
+            [format!(
+                "Destination {} of control case was changed to 255 outside of kool",
+                dest
+            )]
+        );
+    }
+
+    #[test]
+    fn writes_the_default_percent_while_the_reading_is_stale() {
+        let fixture = Fixture::new("stale");
+        let dest = fixture.dest("pwm1");
+        let temp = fixture.dir.join("temp1_input");
+        fs::write(&temp, "50000\n").unwrap();
+
+        let mut control = ControlBuilder::new("case")
+            .source("file", &temp.to_string_lossy())
+            .checks(source::Checks {
+                max_unchanged_polls: Some(1),
+                ..Default::default()
+            })
+            .dest(&dest, 0, 200)
+            .points(&[(30000, 20), (70000, 100)])
+            .default_percent(70)
+            .build(&Context::default())
+            .unwrap();
+
+        control.control(&emitter()).unwrap();
+        assert_eq!(pwm(Path::new(&dest)), "120");
+
+        let err = control.control(&emitter()).unwrap_err();
+        assert_eq!(err.code(), Code::StaleReading);
+        assert_eq!(pwm(Path::new(&dest)), "140");
+
+        fs::write(&temp, "30000\n").unwrap();
+        control.control(&emitter()).unwrap();
+        assert_eq!(pwm(Path::new(&dest)), "40");
+    }
+
+    fn stall_control(fixture: &Fixture, rpm: &Path) -> Control {
+        let mut config = ControlBuilder::new("pump")
+            .dest_config(DestConfig {
+                path: fixture.dest("pwm1"),
+                dest_min: 0,
+                dest_max: 255,
+                scale: None,
+                offset: None,
+                rpm_path: Some(rpm.to_string_lossy().to_string()),
+            })
+            .points(&[(30000, 0), (70000, 100)])
+            .config();
+        config.stall_cycles = Some(2);
+
+        Control::with_source(config, fixture.source(), &Context::default()).unwrap()
+    }
+
+    #[test]
+    fn reports_a_fan_at_zero_rpm_above_the_stall_percent_once() {
+        let fixture = Fixture::new("stall");
+        let rpm = fixture.dir.join("fan1_input");
+        fs::write(&rpm, "0\n").unwrap();
+
+        let mut control = stall_control(&fixture, &rpm);
+
+        // 50%, the first tick is spent spinning up
+        fixture.set(Ok(50000));
+        control.control(&emitter()).unwrap();
+        control.control(&emitter()).unwrap();
+        assert!(control.healthy());
+
+        let err = control.control(&emitter()).unwrap_err();
+        assert_eq!(err.code(), Code::FanStall);
+        assert!(!control.healthy());
+
+        // still stalled, but already reported
+        control.control(&emitter()).unwrap();
+        assert!(!control.healthy());
+
+        fs::write(&rpm, "1200\n").unwrap();
+        control.control(&emitter()).unwrap();
+        assert!(control.healthy());
+    }
+
+    #[test]
+    fn gives_a_fan_raised_above_the_stall_percent_a_tick_to_spin_up() {
+        let fixture = Fixture::new("stall-grace");
+        let rpm = fixture.dir.join("fan1_input");
+        fs::write(&rpm, "0\n").unwrap();
+
+        let mut control = stall_control(&fixture, &rpm);
+
+        // 10% is below the stall percent, a stopped fan is fine
+        fixture.set(Ok(34000));
+        for _ in 0..4 {
+            control.control(&emitter()).unwrap();
+        }
+
+        fixture.set(Ok(50000));
+        control.control(&emitter()).unwrap();
+        control.control(&emitter()).unwrap();
+
+        let err = control.control(&emitter()).unwrap_err();
+        assert_eq!(err.code(), Code::FanStall);
+
+        // back below and raised again starts over
+        fixture.set(Ok(34000));
+        control.control(&emitter()).unwrap();
+        assert!(control.healthy());
+
+        fixture.set(Ok(50000));
+        control.control(&emitter()).unwrap();
+        fs::write(&rpm, "800\n").unwrap();
+        control.control(&emitter()).unwrap();
+        control.control(&emitter()).unwrap();
+        assert!(control.healthy());
     }
 }
@@ -0,0 +1,573 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::{fs, time::Duration};
+
+use crate::async_pool;
+use crate::calibrate;
+use crate::client;
+use crate::control::Config;
+use crate::control::Context;
+use crate::control::Control;
+use crate::emergency::Emergency;
+use crate::error::{Code, Error};
+use crate::event::{Event, Payload, Severity};
+use crate::logger::{self, LogConfig};
+use crate::metrics::{Metrics, MetricsConfig};
+use crate::notifier::{NotifierConfig, Notifiers, Throttle};
+use crate::profile::{ProfileConfig, ProfileFile, Profiles};
+use crate::rules::{Rule, RuleConfig, Rules};
+use crate::schedule::{self, Caps, ScheduleConfig, SystemClock};
+use crate::socket::Server;
+use crate::source;
+use crate::telemetry::{Recorder, TelemetryConfig};
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct MainConfig {
+    thread_count: Option<usize>,
+    timer_resolution: Option<u64>,
+    // run once when a control crosses its critical temperature, e.g. to suspend
+    critical_command: Option<String>,
+    critical_args: Option<Vec<String>>,
+    // defaults to desktop notifications for errors
+    notifier: Option<Vec<NotifierConfig>>,
+    // in seconds, repeated failures of a control are summed up once per window, defaults to 300
+    notification_window: Option<u64>,
+    // defaults to stderr
+    log: Option<LogConfig>,
+    // records readings and outputs of every control
+    telemetry: Option<TelemetryConfig>,
+    // prometheus metrics over http or for the node_exporter textfile collector
+    metrics: Option<MetricsConfig>,
+    // unix socket for kool status/set, e.g. /run/kool.sock
+    socket: Option<String>,
+    // profile used on start, without one the curves of the controls are used
+    default_profile: Option<String>,
+    // writing a profile name to this file switches to it
+    profile_file: Option<String>,
+    // in milliseconds, how often the rules are checked, defaults to 2000
+    rule_interval: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct FileConfig {
+    control: Vec<Config>,
+    source: Option<Vec<source::SharedConfig>>,
+    profile: Option<Vec<ProfileConfig>>,
+    // switch profiles on power state or running processes
+    rule: Option<Vec<RuleConfig>>,
+    // quiet hours, switch profiles or cap the duty during a time window
+    schedule: Option<Vec<ScheduleConfig>>,
+    main: Option<MainConfig>,
+    // possibly add the option for dynamic threads
+}
+
+fn load_config(path: &str) -> Result<FileConfig, Error> {
+    let tomls = fs::read_to_string(path).map_err(|err| {
+        Error::new(
+            Code::General,
+            format!("Cannot read from file {}: {}", path, err),
+        )
+    })?;
+
+    toml::from_str(&tomls).map_err(|err| {
+        Error::new(
+            Code::UnableToParse,
+            format!("Cannot parse {}: {}", path, err),
+        )
+    })
+}
+
+fn notification_summary(severity: Severity) -> &'static str {
+    match severity {
+        Severity::Info => "Kool",
+        Severity::Warn => "Kool Warning",
+        Severity::Error => "Kool Error",
+        Severity::Critical => "Kool Critical Temperature",
+    }
+}
+
+// readings and outputs are emitted on every tick, not worth printing or notifying
+fn is_telemetry(e: &Event) -> bool {
+    matches!(e.payload, Payload::Reading { .. } | Payload::Output { .. })
+}
+
+// lets the throttle know about recoveries, a tick without an error isn't one on its own
+fn report(
+    throttle: &Throttle,
+    origin: &str,
+    result: Result<(), Error>,
+    healthy: bool,
+) -> Option<Error> {
+    match result {
+        Ok(()) => {
+            if healthy {
+                throttle.success(origin);
+            }
+            None
+        }
+        Err(err) => Some(err),
+    }
+}
+
+fn calibrate_command(args: &[String]) -> i32 {
+    let options = match calibrate::Options::parse(args) {
+        Ok(options) => options,
+        Err(message) => {
+            println!("{}", message);
+            return 1;
+        }
+    };
+
+    println!(
+        "Calibrating {}, this takes a while, the fan will stop during calibration",
+        options.pwm_path
+    );
+
+    calibrate::handle_interrupts();
+
+    match calibrate::calibrate(&options) {
+        Ok(calibration) => {
+            print!("{}", calibration.report(&options));
+            0
+        }
+        Err(err) => {
+            println!("Calibration failed: {}", err);
+            1
+        }
+    }
+}
+
+// schedules go first, quiet hours win over the other rules
+fn create_rules(
+    schedules: &[ScheduleConfig],
+    rules: &[RuleConfig],
+    profiles: &Profiles,
+) -> Result<(Rules, Caps), Error> {
+    let (mut created, caps) = schedule::create(schedules, Arc::new(SystemClock {}))?;
+
+    for rule in rules {
+        created.push(Rule::from_config(rule)?);
+    }
+
+    Ok((Rules::new(created, profiles)?, caps))
+}
+
+// builds everything in the config without starting it, every error is reported,
+// the destinations are only looked up so it runs without root
+fn check_command(args: &[String]) -> i32 {
+    if args.len() != 1 {
+        println!("Usage: kool check <config>");
+        return 1;
+    }
+
+    let config = match load_config(&args[0]) {
+        Ok(config) => config,
+        Err(err) => {
+            println!("{}", err);
+            return 1;
+        }
+    };
+
+    let mut errors = Vec::new();
+
+    if let Some(main) = &config.main {
+        if let Err(err) = Notifiers::from_configs(main.notifier.as_ref()) {
+            errors.push(err);
+        }
+
+        if let Err(err) = logger::create(main.log.as_ref()) {
+            errors.push(err);
+        }
+    }
+
+    let default_profile = config
+        .main
+        .as_ref()
+        .and_then(|m| m.default_profile.as_ref());
+
+    let profiles = Profiles::new(
+        config.profile.clone().unwrap_or_default(),
+        &config.control,
+        default_profile,
+    )
+    .unwrap_or_else(|err| {
+        errors.push(err);
+        Profiles::new(Vec::new(), &[], None).unwrap()
+    });
+
+    let caps = match create_rules(
+        &config.schedule.clone().unwrap_or_default(),
+        &config.rule.clone().unwrap_or_default(),
+        &profiles,
+    ) {
+        Ok((_, caps)) => caps,
+        Err(err) => {
+            errors.push(err);
+            schedule::create(&[], Arc::new(SystemClock {})).unwrap().1
+        }
+    };
+
+    let mut context = Context {
+        shared: HashMap::new(),
+        emergency: Arc::new(Emergency::new(None, None)),
+        profiles: Arc::new(profiles),
+        caps: Arc::new(caps),
+    };
+
+    for source_config in config.source.unwrap_or_default() {
+        match source::SharedSource::new(&source_config, &context.shared) {
+            Ok(src) => {
+                context.shared.insert(src.name().clone(), Arc::new(src));
+            }
+            Err(err) => errors.push(err),
+        }
+    }
+
+    for control_config in config.control.iter() {
+        if let Err(err) = Control::check(control_config, &context) {
+            errors.push(err);
+        }
+    }
+
+    for err in errors.iter() {
+        println!("{}", err);
+    }
+
+    if errors.is_empty() {
+        println!("Config {} is valid", args[0]);
+        0
+    } else {
+        println!("{} errors in {}", errors.len(), args[0]);
+        1
+    }
+}
+
+fn client_command(command: &str, args: &[String]) -> i32 {
+    let options = match client::Options::parse(command, args) {
+        Ok(options) => options,
+        Err(message) => {
+            println!("{}", message);
+            return 1;
+        }
+    };
+
+    match client::run(&options) {
+        Ok(()) => 0,
+        Err(err) => {
+            println!("{}", err);
+            1
+        }
+    }
+}
+
+// everything the kool binary does, args as passed to it, returns the exit code
+pub fn run(args: &[String]) -> i32 {
+    if args.len() < 2 {
+        println!("Argument for config path file missing");
+        return 1;
+    }
+
+    if args[1] == "calibrate" {
+        return calibrate_command(&args[2..]);
+    }
+
+    if args[1] == "check" {
+        return check_command(&args[2..]);
+    }
+
+    if matches!(
+        args[1].as_str(),
+        "status" | "set" | "release" | "watch" | "profile"
+    ) {
+        return client_command(&args[1], &args[2..]);
+    }
+
+    let path_to_config = &args[1];
+
+    println!("Loading config {path_to_config}");
+
+    let config = match load_config(path_to_config) {
+        Ok(config) => config,
+        Err(err) => {
+            println!("{}", err);
+            return 1;
+        }
+    };
+
+    let mut thread_count = 10;
+    let mut timer_resolution = 1;
+    let mut emergency = Emergency::new(None, None);
+    let mut notifiers = Notifiers::from_configs(None);
+    let mut notification_window = 300;
+    let mut logger = logger::create(None);
+    let mut recorder = None;
+    let mut metrics_config = None;
+    let mut socket_path = None;
+    let mut default_profile = None;
+    let mut profile_file = None;
+    let mut rule_interval = 2000;
+
+    if let Some(main) = config.main {
+        emergency = Emergency::new(main.critical_command, main.critical_args);
+        notifiers = Notifiers::from_configs(main.notifier.as_ref());
+        logger = logger::create(main.log.as_ref());
+
+        if let Some(telemetry) = &main.telemetry {
+            recorder = Some(Recorder::new(telemetry));
+        }
+
+        metrics_config = main.metrics;
+        socket_path = main.socket;
+        default_profile = main.default_profile;
+        profile_file = main.profile_file;
+
+        if let Some(interval) = main.rule_interval {
+            rule_interval = interval;
+        }
+
+        if let Some(window) = main.notification_window {
+            notification_window = window;
+        }
+
+        if let Some(th) = main.thread_count {
+            thread_count = th;
+        }
+
+        if let Some(res) = main.timer_resolution {
+            timer_resolution = res;
+        }
+    }
+
+    let notifiers = match notifiers {
+        Ok(notifiers) => Arc::new(notifiers),
+        Err(err) => {
+            println!("{}", err);
+            return 1;
+        }
+    };
+
+    let logger = match logger {
+        Ok(logger) => logger,
+        Err(err) => {
+            println!("{}", err);
+            return 1;
+        }
+    };
+
+    let mut async_pool =
+        async_pool::AsyncPool::new(thread_count, Duration::from_millis(timer_resolution));
+
+    let throttle = Arc::new(Throttle::new(
+        Arc::clone(&notifiers),
+        Duration::from_secs(notification_window),
+    ));
+
+    // summaries go out when the window ends, also when the failures stopped
+    let job_throttle = Arc::clone(&throttle);
+    async_pool.attach_job("notifications", Duration::from_secs(1), move |_| {
+        job_throttle.flush();
+        None
+    });
+
+    match recorder {
+        Some(Ok(recorder)) => {
+            async_pool.connect_filtered_listener(is_telemetry, move |e| recorder.record(&e));
+        }
+        Some(Err(err)) => {
+            println!("{}", err);
+            return 1;
+        }
+        None => {}
+    }
+
+    if let Some(metrics_config) = metrics_config {
+        let metrics = Arc::new(Metrics::new(async_pool.lateness()));
+
+        let listener_metrics = Arc::clone(&metrics);
+        async_pool.connect_listener(move |e| listener_metrics.record(&e));
+
+        if let Some(address) = &metrics_config.listen {
+            if let Err(err) = Metrics::serve(Arc::clone(&metrics), address) {
+                println!("{}", err);
+                return 1;
+            }
+        }
+
+        if let Some(path) = metrics_config.textfile {
+            let interval = Duration::from_millis(metrics_config.textfile_interval.unwrap_or(1000));
+
+            async_pool.attach_job("metrics", interval, move |_| {
+                metrics.write_textfile(&path).err()
+            });
+        }
+    }
+
+    let listener_notifiers = Arc::clone(&notifiers);
+    let listener_throttle = Arc::clone(&throttle);
+
+    async_pool.connect_filtered_listener(
+        |e| !is_telemetry(e),
+        move |e| {
+            logger.log(&e);
+
+            let summary = notification_summary(e.severity);
+
+            match (&e.payload, &e.origin) {
+                (Payload::Error(err), Some(origin)) => {
+                    listener_throttle.failure(origin, e.severity, summary, err);
+                }
+                _ => listener_notifiers.notify(e.severity, summary, &e.payload.to_string()),
+            }
+        },
+    );
+
+    let profiles = match Profiles::new(
+        config.profile.unwrap_or_default(),
+        &config.control,
+        default_profile.as_ref(),
+    ) {
+        Ok(profiles) => Arc::new(profiles),
+        Err(err) => {
+            println!("{}", err);
+            return 1;
+        }
+    };
+
+    Profiles::handle_sigusr1();
+
+    let job_profiles = Arc::clone(&profiles);
+    let profile_file = Mutex::new(profile_file.map(ProfileFile::new));
+
+    async_pool.attach_job("profile", Duration::from_millis(1000), move |emitter| {
+        let switched = job_profiles.cycle_requested().or_else(|| {
+            profile_file
+                .lock()
+                .unwrap()
+                .as_mut()
+                .and_then(|file| file.check(&job_profiles))
+        });
+
+        match switched {
+            Some(Ok(name)) => {
+                emitter.emit(Event::log(format!("Switched to profile {}", name)));
+                None
+            }
+            Some(Err(err)) => Some(err),
+            None => None,
+        }
+    });
+
+    let rules = create_rules(
+        &config.schedule.unwrap_or_default(),
+        &config.rule.unwrap_or_default(),
+        &profiles,
+    );
+
+    let (rules, caps) = match rules {
+        Ok(rules) => rules,
+        Err(err) => {
+            println!("{}", err);
+            return 1;
+        }
+    };
+
+    if !rules.is_empty() {
+        let job_profiles = Arc::clone(&profiles);
+        let rules = Mutex::new(rules);
+
+        async_pool.attach_job(
+            "rules",
+            Duration::from_millis(rule_interval),
+            move |emitter| match rules.lock().unwrap().apply(&job_profiles) {
+                Some(Ok(switched)) => {
+                    emitter.emit(Event::log(format!("Switched to {}", switched)));
+                    None
+                }
+                Some(Err(err)) => Some(err),
+                None => None,
+            },
+        );
+    }
+
+    let mut context = Context {
+        shared: HashMap::new(),
+        emergency: Arc::new(emergency),
+        profiles: Arc::clone(&profiles),
+        caps: Arc::new(caps),
+    };
+
+    for source_config in config.source.unwrap_or_default() {
+        match source::SharedSource::new(&source_config, &context.shared) {
+            Ok(src) => {
+                let interval = *src.get_interval();
+                let src = Arc::new(src);
+
+                // so the controls have a reading before the first poll
+                let _ = src.poll();
+
+                context.shared.insert(src.name().clone(), Arc::clone(&src));
+
+                let throttle = Arc::clone(&throttle);
+
+                async_pool.attach_job(&src.name().clone(), interval, move |_| {
+                    report(&throttle, src.name(), src.poll(), true)
+                });
+            }
+            Err(err) => {
+                notifiers.notify(
+                    Severity::Error,
+                    notification_summary(Severity::Error),
+                    err.message(),
+                );
+            }
+        }
+    }
+
+    let mut controls = HashMap::new();
+
+    for control_config in config.control {
+        let name = control_config.name.clone();
+
+        match Control::new(control_config, &context) {
+            Ok(control) => {
+                let interval = *control.get_interval();
+                let control = Arc::new(Mutex::new(control));
+                let throttle = Arc::clone(&throttle);
+
+                controls.insert(name.clone(), Arc::clone(&control));
+
+                async_pool.attach_job(&name.clone(), interval, move |emitter| {
+                    let mut control = control.lock().unwrap();
+                    let result = control.control(emitter);
+
+                    report(&throttle, &name, result, control.healthy())
+                });
+            }
+            Err(err) => {
+                notifiers.notify(
+                    Severity::Error,
+                    notification_summary(Severity::Error),
+                    err.message(),
+                );
+            }
+        }
+    }
+
+    if let Some(socket_path) = socket_path {
+        let path_to_config = path_to_config.clone();
+
+        let server = Arc::new(Server::new(controls, profiles, move || {
+            load_config(&path_to_config).map(|config| config.control)
+        }));
+
+        if let Err(err) = Server::listen(server, &socket_path, async_pool.submitter()) {
+            println!("{}", err);
+            return 1;
+        }
+    }
+
+    async_pool.wait();
+
+    0
+}
@@ -0,0 +1,330 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    time::{Duration, Instant},
+};
+
+use crate::error::{Code, Error};
+use crate::source::{FileSource, Source};
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct DestConfig {
+    pub path: String,
+    pub dest_min: i64,
+    pub dest_max: i64,
+    // applied to the curve output before mapping to [dest_min, dest_max]
+    // percent = percent * scale + offset
+    pub scale: Option<f64>,
+    pub offset: Option<f64>,
+    // fan speed feedback, usually hwmon fanN_input
+    pub rpm_path: Option<String>,
+}
+
+pub fn lerp(in_min: f64, in_max: f64, in_current: f64, out_min: f64, out_max: f64) -> f64 {
+    let in_range = in_max - in_min;
+    let in_cur = in_current - in_min;
+
+    let percent = in_cur / in_range;
+
+    (out_max - out_min) * percent + out_min
+}
+
+/// An output written by a control, usually a hwmon pwm file.
+pub struct Destination {
+    path: String,
+    file: fs::File,
+    min: i64,
+    max: i64,
+    scale: f64,
+    offset: f64,
+    rpm: Option<FileSource>,
+    last_percent: f64,
+    last_rpm: Option<i64>,
+    stalled_cycles: u32,
+    // duty at the previous stall check, a fan just raised above the threshold gets a cycle to spin up
+    checked_percent: f64,
+    // identical values are only rewritten every refresh_interval
+    last_raw: Option<i64>,
+    last_write: Instant,
+    refresh_interval: Duration,
+    // value found on refresh that we didn't write
+    overridden: Option<i64>,
+}
+
+impl Destination {
+    /// Opens the output, and the rpm file when there is one.
+    pub fn new(config: &DestConfig) -> Result<Self, Error> {
+        let rpm = match &config.rpm_path {
+            Some(rpm_path) => Some(FileSource::new(rpm_path).ok_or_else(|| {
+                Error::new(
+                    Code::General,
+                    format!("Cannot open/find rpm file: {}", rpm_path),
+                )
+            })?),
+            None => None,
+        };
+
+        match OpenOptions::new().read(true).write(true).open(&config.path) {
+            Ok(file) => Ok(Self {
+                path: config.path.clone(),
+                file,
+                min: config.dest_min,
+                max: config.dest_max,
+                scale: config.scale.unwrap_or(1.0),
+                offset: config.offset.unwrap_or(0.0),
+                rpm,
+                last_percent: 0.0,
+                last_rpm: None,
+                stalled_cycles: 0,
+                checked_percent: 0.0,
+                last_raw: None,
+                last_write: Instant::now(),
+                refresh_interval: Duration::from_secs(10),
+                overridden: None,
+            }),
+            Err(err) => Err(Error::new(
+                Code::CannotOpenDestinationFile,
+                format!("Cannot open destination {}: {}", config.path, err),
+            )),
+        }
+    }
+
+    /// Maps `percent` to `dest_min..dest_max` and writes it, returns the value written.
+    pub fn write_percent(&mut self, percent: f64) -> Result<i64, Error> {
+        let percent = (percent * self.scale + self.offset).clamp(0.0, 100.0);
+        self.last_percent = percent;
+
+        let pwm = lerp(0.0, 100.0, percent, self.min as f64, self.max as f64) as i64;
+
+        self.write_raw(pwm)?;
+
+        Ok(pwm)
+    }
+
+    // stopped fans get 0, dest_min and the offset would keep them spinning
+    pub fn write_stop(&mut self) -> Result<(), Error> {
+        self.last_percent = 0.0;
+        self.write_raw(0)
+    }
+
+    pub fn write_max(&mut self) -> Result<(), Error> {
+        self.last_percent = 100.0;
+        self.write_raw(self.max)
+    }
+
+    pub fn set_refresh_interval(&mut self, refresh_interval: Duration) {
+        self.refresh_interval = refresh_interval;
+    }
+
+    pub fn take_overridden(&mut self) -> Option<i64> {
+        self.overridden.take()
+    }
+
+    pub fn path(&self) -> &String {
+        &self.path
+    }
+
+    pub fn rpm(&mut self) -> Option<Result<i64, Error>> {
+        let rpm = self.rpm.as_mut().map(|rpm| rpm.get(Duration::ZERO));
+
+        self.last_rpm = match &rpm {
+            Some(Ok(rpm)) => Some(*rpm),
+            _ => None,
+        };
+
+        rpm
+    }
+
+    pub fn last_rpm(&self) -> Option<i64> {
+        self.last_rpm
+    }
+
+    // percent after scale and offset
+    pub fn percent(&self) -> f64 {
+        self.last_percent
+    }
+
+    // last value written
+    pub fn raw(&self) -> Option<i64> {
+        self.last_raw
+    }
+
+    // returns for how many cycles in a row the fan hasn't been spinning
+    // while the commanded duty was above the threshold
+    pub fn check_stall(&mut self, threshold: f64) -> Result<u32, Error> {
+        let rpm = match self.rpm() {
+            Some(rpm) => rpm?,
+            None => return Ok(0),
+        };
+
+        let spinning_up = self.checked_percent <= threshold;
+        self.checked_percent = self.last_percent;
+
+        if rpm == 0 && self.last_percent > threshold && !spinning_up {
+            self.stalled_cycles += 1;
+        } else {
+            self.stalled_cycles = 0;
+        }
+
+        Ok(self.stalled_cycles)
+    }
+
+    fn read_raw(&mut self) -> Option<i64> {
+        let mut buffer = String::new();
+
+        let _ = self.file.seek(SeekFrom::Start(0));
+        self.file.read_to_string(&mut buffer).ok()?;
+
+        buffer.trim().parse::<i64>().ok()
+    }
+
+    pub fn write_raw(&mut self, pwm: i64) -> Result<(), Error> {
+        // writing the same value wakes up the EC on some laptops for nothing
+        if self.last_raw == Some(pwm) {
+            if self.last_write.elapsed() < self.refresh_interval {
+                return Ok(());
+            }
+
+            // firmware or another tool might have changed it behind our back
+            if let Some(current) = self.read_raw() {
+                if current != pwm {
+                    self.overridden = Some(current);
+                }
+            }
+        }
+
+        // sysfs doesn't care, but a regular file would keep the previous value appended
+        let _ = self.file.set_len(0);
+        let _ = self.file.seek(SeekFrom::Start(0));
+
+        match self.file.write_all(pwm.to_string().as_bytes()) {
+            Ok(_) => {
+                let _ = self.file.flush();
+                self.last_raw = Some(pwm);
+                self.last_write = Instant::now();
+                Ok(())
+            }
+            Err(err) => {
+                self.last_raw = None;
+                Err(Error::new(
+                    Code::UnableToWrite,
+                    format!("Unabe to write to destination {}: {}", self.path, err),
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{path::PathBuf, process, thread};
+
+    struct Fixture {
+        dir: PathBuf,
+        path: PathBuf,
+    }
+
+    impl Fixture {
+        fn new(name: &str) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("kool-destination-{}-{}", process::id(), name));
+
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+
+            let path = dir.join("pwm1");
+            fs::write(&path, "0").unwrap();
+
+            Self { dir, path }
+        }
+
+        fn destination(&self) -> Destination {
+            Destination::new(&DestConfig {
+                path: self.path.to_string_lossy().into_owned(),
+                dest_min: 0,
+                dest_max: 255,
+                scale: None,
+                offset: None,
+                rpm_path: None,
+            })
+            .unwrap()
+        }
+
+        fn pwm(&self) -> String {
+            fs::read_to_string(&self.path).unwrap()
+        }
+
+        // what firmware or another tool would do
+        fn change(&self, pwm: &str) {
+            fs::write(&self.path, pwm).unwrap();
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn skips_identical_writes_until_the_refresh_interval() {
+        let fixture = Fixture::new("skip");
+        let mut destination = fixture.destination();
+
+        destination.write_raw(128).unwrap();
+        assert_eq!(fixture.pwm(), "128");
+
+        // a skipped write leaves the file alone
+        fixture.change("");
+        destination.write_raw(128).unwrap();
+        assert_eq!(fixture.pwm(), "");
+
+        // other values are written right away
+        destination.write_raw(100).unwrap();
+        assert_eq!(fixture.pwm(), "100");
+        assert_eq!(destination.raw(), Some(100));
+    }
+
+    #[test]
+    fn rewrites_identical_values_after_the_refresh_interval() {
+        let fixture = Fixture::new("refresh");
+        let mut destination = fixture.destination();
+
+        destination.set_refresh_interval(Duration::from_millis(20));
+        destination.write_raw(128).unwrap();
+
+        fixture.change("");
+        thread::sleep(Duration::from_millis(30));
+
+        destination.write_raw(128).unwrap();
+        assert_eq!(fixture.pwm(), "128");
+        // an unreadable value isn't an override
+        assert_eq!(destination.take_overridden(), None);
+    }
+
+    #[test]
+    fn reports_values_changed_outside_of_kool_on_refresh() {
+        let fixture = Fixture::new("overridden");
+        let mut destination = fixture.destination();
+
+        destination.write_raw(128).unwrap();
+        fixture.change("255\n");
+
+        // not noticed before the refresh
+        destination.write_raw(128).unwrap();
+        assert_eq!(destination.take_overridden(), None);
+
+        destination.set_refresh_interval(Duration::ZERO);
+        destination.write_raw(128).unwrap();
+
+        assert_eq!(fixture.pwm(), "128");
+        assert_eq!(destination.take_overridden(), Some(255));
+        assert_eq!(destination.take_overridden(), None);
+
+        // the value we wrote ourselves isn't reported
+        destination.write_raw(128).unwrap();
+        assert_eq!(destination.take_overridden(), None);
+    }
+}
@@ -0,0 +1,76 @@
+use std::{
+    collections::HashSet,
+    process::Command,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    thread,
+};
+
+use crate::destination::{DestConfig, Destination};
+
+// shared between all controls, once any control crosses its critical temperature
+// every registered output goes to maximum until all of them cool down
+pub struct Emergency {
+    active: AtomicBool,
+    raised_by: Mutex<HashSet<String>>,
+    outputs: Mutex<Vec<Destination>>,
+    command: Option<(String, Vec<String>)>,
+}
+
+impl Emergency {
+    pub fn new(command: Option<String>, args: Option<Vec<String>>) -> Self {
+        Self {
+            active: AtomicBool::new(false),
+            raised_by: Mutex::new(HashSet::new()),
+            outputs: Mutex::new(Vec::new()),
+            command: command.map(|command| (command, args.unwrap_or_default())),
+        }
+    }
+
+    pub fn active(&self) -> bool {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    // the outputs are opened separately so they can be driven without waiting on the controls
+    pub fn register(&self, configs: &[DestConfig]) {
+        let mut outputs = self.outputs.lock().unwrap();
+
+        for config in configs {
+            if let Ok(dest) = Destination::new(config) {
+                outputs.push(dest);
+            }
+        }
+    }
+
+    // returns true when this starts the emergency
+    pub fn raise(&self, name: &str) -> bool {
+        let mut raised_by = self.raised_by.lock().unwrap();
+
+        if !raised_by.insert(name.to_string()) || self.active.swap(true, Ordering::SeqCst) {
+            return false;
+        }
+
+        for dest in self.outputs.lock().unwrap().iter_mut() {
+            let _ = dest.write_max();
+        }
+
+        // reaped on its own thread, suspending might take a while
+        if let Some((command, args)) = &self.command {
+            if let Ok(mut child) = Command::new(command).args(args).spawn() {
+                thread::spawn(move || child.wait());
+            }
+        }
+
+        true
+    }
+
+    pub fn clear(&self, name: &str) {
+        let mut raised_by = self.raised_by.lock().unwrap();
+
+        if raised_by.remove(name) && raised_by.is_empty() {
+            self.active.store(false, Ordering::SeqCst);
+        }
+    }
+}
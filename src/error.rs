@@ -1,6 +1,7 @@
 use std::fmt;
 
-#[derive(Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[non_exhaustive]
 pub enum Code {
     General = 0x0000,
     SourceTypeIsRequired,
@@ -10,8 +11,24 @@ pub enum Code {
     UnableToParse,
     UnableToWrite,
     InvalidConfigCurvePoints,
+    InvalidSourceConfig,
+    StaleReading,
+    FanStall,
+    CriticalTemperature,
+    InvalidNotifierConfig,
+    InvalidLogConfig,
+    InvalidTelemetryConfig,
+    InvalidMetricsConfig,
+    InvalidSocketConfig,
+    InvalidRequest,
+    InvalidProfileConfig,
+    InvalidRuleConfig,
+    InvalidScheduleConfig,
+    InvalidExpression,
+    OutOfRange,
 }
 
+/// A failure with the [`Code`] telling what kind it is.
 pub struct Error {
     code: Code,
     message: String,
@@ -37,6 +54,8 @@ impl fmt::Display for Error {
     }
 }
 
+impl std::error::Error for Error {}
+
 impl fmt::Debug for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
@@ -1,8 +1,146 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
 use crate::error;
 
-pub enum Event {
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+#[non_exhaustive]
+pub enum Severity {
+    Info,
+    Warn,
+    Error,
+    Critical,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Severity::Info => "info",
+            Severity::Warn => "warn",
+            Severity::Error => "error",
+            Severity::Critical => "critical",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+#[non_exhaustive]
+pub enum Payload {
     Log(String),
-    Warn(String),
+    // value read from the source of a control, raw is before any filtering,
+    // label describes readings that aren't a plain value like a difference
+    Reading {
+        raw: i64,
+        value: i64,
+        label: Option<String>,
+    },
+    // value written to one of the destinations of a control,
+    // target is the output of the control and percent after the scaling of the destination
+    Output {
+        dest: String,
+        target: f64,
+        percent: f64,
+        pwm: i64,
+        rpm: Option<i64>,
+    },
+    State {
+        from: String,
+        to: String,
+    },
     Error(error::Error),
-    LogError(String),
+}
+
+impl fmt::Display for Payload {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Payload::Log(message) => write!(f, "{}", message),
+            Payload::Reading {
+                value,
+                label: Some(label),
+                ..
+            } => write!(f, "reading {} ({})", value, label),
+            Payload::Reading { value, .. } => write!(f, "reading {}", value),
+            Payload::Output {
+                dest,
+                percent,
+                pwm,
+                rpm,
+                ..
+            } => {
+                write!(f, "output {} {:.1}% pwm {}", dest, percent, pwm)?;
+
+                if let Some(rpm) = rpm {
+                    write!(f, " rpm {}", rpm)?;
+                }
+
+                Ok(())
+            }
+            Payload::State { from, to } => write!(f, "state {} -> {}", from, to),
+            Payload::Error(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+/// Something that happened in a job, passed to the listeners of the pool.
+pub struct Event {
+    pub severity: Severity,
+    pub timestamp: SystemTime,
+    // name of the control or job the event came from
+    pub origin: Option<String>,
+    pub payload: Payload,
+}
+
+impl Event {
+    pub fn new(severity: Severity, payload: Payload) -> Self {
+        Self {
+            severity,
+            timestamp: SystemTime::now(),
+            origin: None,
+            payload,
+        }
+    }
+
+    pub fn log(message: String) -> Self {
+        Event::new(Severity::Info, Payload::Log(message))
+    }
+
+    pub fn warn(message: String) -> Self {
+        Event::new(Severity::Warn, Payload::Log(message))
+    }
+
+    pub fn error(err: error::Error) -> Self {
+        let severity = if err.code() == error::Code::CriticalTemperature {
+            Severity::Critical
+        } else {
+            Severity::Error
+        };
+
+        Event::new(severity, Payload::Error(err))
+    }
+
+    pub fn with_origin(mut self, origin: &str) -> Self {
+        self.origin = Some(origin.to_string());
+        self
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let time = self
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        write!(f, "{}.{:03} ", time.as_secs(), time.subsec_millis())?;
+
+        match &self.origin {
+            Some(origin) => write!(f, "[{}] {}: {}", self.severity, origin, self.payload),
+            None => write!(f, "[{}] {}", self.severity, self.payload),
+        }
+    }
 }
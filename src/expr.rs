@@ -0,0 +1,514 @@
+use std::{iter::Peekable, str::Chars, sync::Arc, time::Duration};
+
+use crate::error::{Code, Error};
+use crate::source::{Registry, SharedSource, Source};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Function {
+    Min,
+    Max,
+    Avg,
+    Abs,
+    Clamp,
+}
+
+impl Function {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "min" => Some(Function::Min),
+            "max" => Some(Function::Max),
+            "avg" => Some(Function::Avg),
+            "abs" => Some(Function::Abs),
+            "clamp" => Some(Function::Clamp),
+            _ => None,
+        }
+    }
+
+    fn accepts(&self, count: usize) -> bool {
+        match self {
+            Function::Min | Function::Max | Function::Avg => count >= 1,
+            Function::Abs => count == 1,
+            Function::Clamp => count == 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Number(f64),
+    // index into the variables of the source
+    Variable(usize),
+    Negate(Box<Expr>),
+    Binary(char, Box<Expr>, Box<Expr>),
+    Call(Function, Vec<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Name(String),
+    Symbol(char),
+}
+
+// deeper nesting is refused instead of running out of stack while parsing,
+// longer expressions instead of evaluating a chain of thousands of operators
+const MAX_DEPTH: usize = 32;
+const MAX_TOKENS: usize = 256;
+
+fn invalid(expression: &str, message: String) -> Error {
+    Error::new(
+        Code::InvalidExpression,
+        format!("{} in expression {}", message, expression),
+    )
+}
+
+fn tokenize(expression: &str) -> Result<Vec<Token>, Error> {
+    let mut tokens = Vec::new();
+    let mut chars: Peekable<Chars> = expression.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_digit() || c == '.' {
+            let mut number = String::new();
+
+            while let Some(&c) = chars.peek() {
+                if !c.is_ascii_digit() && c != '.' {
+                    break;
+                }
+                number.push(c);
+                chars.next();
+            }
+
+            // exponent like 1e3 or 2.5E-2, an e not followed by digits is left to the names
+            if let Some(&e @ ('e' | 'E')) = chars.peek() {
+                let mut ahead = chars.clone();
+                ahead.next();
+
+                let sign = match ahead.peek() {
+                    Some(&sign @ ('+' | '-')) => {
+                        ahead.next();
+                        Some(sign)
+                    }
+                    _ => None,
+                };
+
+                if ahead.peek().is_some_and(|c| c.is_ascii_digit()) {
+                    number.push(e);
+                    number.extend(sign);
+                    chars = ahead;
+
+                    while let Some(&c) = chars.peek() {
+                        if !c.is_ascii_digit() {
+                            break;
+                        }
+                        number.push(c);
+                        chars.next();
+                    }
+                }
+            }
+
+            let number = number
+                .parse::<f64>()
+                .map_err(|_| invalid(expression, format!("Invalid number {}", number)))?;
+
+            tokens.push(Token::Number(number));
+        } else if c.is_alphabetic() || c == '_' {
+            let mut name = String::new();
+
+            while let Some(&c) = chars.peek() {
+                if !c.is_alphanumeric() && c != '_' {
+                    break;
+                }
+                name.push(c);
+                chars.next();
+            }
+
+            tokens.push(Token::Name(name));
+        } else if "+-*/(),".contains(c) {
+            tokens.push(Token::Symbol(c));
+            chars.next();
+        } else {
+            return Err(invalid(expression, format!("Unexpected character {}", c)));
+        }
+    }
+
+    if tokens.len() > MAX_TOKENS {
+        return Err(invalid(
+            expression,
+            format!("More than {} tokens", MAX_TOKENS),
+        ));
+    }
+
+    Ok(tokens)
+}
+
+// recursive descent over the tokens, the names are resolved against the shared sources
+struct Parser<'a> {
+    expression: &'a str,
+    tokens: Vec<Token>,
+    position: usize,
+    depth: usize,
+    shared: &'a Registry,
+    variables: Vec<(String, Arc<SharedSource>)>,
+}
+
+impl<'a> Parser<'a> {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn expect(&mut self, symbol: char) -> Result<(), Error> {
+        match self.next() {
+            Some(Token::Symbol(c)) if c == symbol => Ok(()),
+            _ => Err(invalid(self.expression, format!("Expected {}", symbol))),
+        }
+    }
+
+    // expr := term (('+' | '-') term)*
+    fn expr(&mut self) -> Result<Expr, Error> {
+        let mut left = self.term()?;
+
+        while let Some(Token::Symbol(op @ ('+' | '-'))) = self.peek().cloned() {
+            self.next();
+            left = Expr::Binary(op, Box::new(left), Box::new(self.term()?));
+        }
+
+        Ok(left)
+    }
+
+    // term := unary (('*' | '/') unary)*
+    fn term(&mut self) -> Result<Expr, Error> {
+        let mut left = self.unary()?;
+
+        while let Some(Token::Symbol(op @ ('*' | '/'))) = self.peek().cloned() {
+            self.next();
+            left = Expr::Binary(op, Box::new(left), Box::new(self.unary()?));
+        }
+
+        Ok(left)
+    }
+
+    // every nested parenthesis, call and minus goes through here
+    fn unary(&mut self) -> Result<Expr, Error> {
+        if self.depth >= MAX_DEPTH {
+            return Err(invalid(
+                self.expression,
+                format!("Nested deeper than {}", MAX_DEPTH),
+            ));
+        }
+
+        self.depth += 1;
+
+        let expr = if self.peek() == Some(&Token::Symbol('-')) {
+            self.next();
+            self.unary().map(|expr| Expr::Negate(Box::new(expr)))
+        } else {
+            self.primary()
+        };
+
+        self.depth -= 1;
+
+        expr
+    }
+
+    fn primary(&mut self) -> Result<Expr, Error> {
+        match self.next() {
+            Some(Token::Number(number)) => Ok(Expr::Number(number)),
+            Some(Token::Symbol('(')) => {
+                let expr = self.expr()?;
+                self.expect(')')?;
+                Ok(expr)
+            }
+            Some(Token::Name(name)) if self.peek() == Some(&Token::Symbol('(')) => {
+                let function = Function::parse(&name).ok_or_else(|| {
+                    invalid(self.expression, format!("Unknown function {}", name))
+                })?;
+
+                self.next();
+
+                let mut args = Vec::new();
+
+                if self.peek() != Some(&Token::Symbol(')')) {
+                    args.push(self.expr()?);
+
+                    while self.peek() == Some(&Token::Symbol(',')) {
+                        self.next();
+                        args.push(self.expr()?);
+                    }
+                }
+
+                self.expect(')')?;
+
+                if !function.accepts(args.len()) {
+                    return Err(invalid(
+                        self.expression,
+                        format!("Wrong number of arguments for {}", name),
+                    ));
+                }
+
+                Ok(Expr::Call(function, args))
+            }
+            Some(Token::Name(name)) => self.variable(name),
+            _ => Err(invalid(
+                self.expression,
+                String::from("Unexpected end or symbol"),
+            )),
+        }
+    }
+
+    fn variable(&mut self, name: String) -> Result<Expr, Error> {
+        if let Some(idx) = self.variables.iter().position(|(n, _)| *n == name) {
+            return Ok(Expr::Variable(idx));
+        }
+
+        let source = self
+            .shared
+            .get(&name)
+            .ok_or_else(|| invalid(self.expression, format!("Unknown source {}", name)))?;
+
+        self.variables.push((name, Arc::clone(source)));
+
+        Ok(Expr::Variable(self.variables.len() - 1))
+    }
+}
+
+impl Expr {
+    fn eval(&self, values: &[f64]) -> Result<f64, Error> {
+        let value = match self {
+            Expr::Number(number) => *number,
+            Expr::Variable(idx) => values[*idx],
+            Expr::Negate(expr) => -expr.eval(values)?,
+            Expr::Binary(op, left, right) => {
+                let (left, right) = (left.eval(values)?, right.eval(values)?);
+
+                match op {
+                    '+' => left + right,
+                    '-' => left - right,
+                    '*' => left * right,
+                    _ if right == 0.0 => {
+                        return Err(Error::new(
+                            Code::InvalidExpression,
+                            String::from("Division by zero in expression"),
+                        ))
+                    }
+                    _ => left / right,
+                }
+            }
+            Expr::Call(function, args) => {
+                let args = args
+                    .iter()
+                    .map(|arg| arg.eval(values))
+                    .collect::<Result<Vec<_>, Error>>()?;
+
+                match function {
+                    Function::Min => args.iter().cloned().fold(f64::INFINITY, f64::min),
+                    Function::Max => args.iter().cloned().fold(f64::NEG_INFINITY, f64::max),
+                    Function::Avg => args.iter().sum::<f64>() / args.len() as f64,
+                    Function::Abs => args[0].abs(),
+                    Function::Clamp => args[0].max(args[1]).min(args[2]),
+                }
+            }
+        };
+
+        Ok(value)
+    }
+}
+
+// combines shared sources, e.g. max(cpu, gpu - 5) or 0.7 * water_in + 0.3 * water_out
+pub struct ExprSource {
+    expr: Expr,
+    variables: Vec<(String, Arc<SharedSource>)>,
+}
+
+impl ExprSource {
+    pub fn new(expression: &str, shared: &Registry) -> Result<Self, Error> {
+        let mut parser = Parser {
+            expression,
+            tokens: tokenize(expression)?,
+            position: 0,
+            depth: 0,
+            shared,
+            variables: Vec::new(),
+        };
+
+        let expr = parser.expr()?;
+
+        if parser.peek().is_some() {
+            return Err(invalid(
+                expression,
+                String::from("Unexpected trailing input"),
+            ));
+        }
+
+        Ok(Self {
+            expr,
+            variables: parser.variables,
+        })
+    }
+}
+
+impl Source for ExprSource {
+    fn get(&mut self, _timeout: Duration) -> Result<i64, Error> {
+        let values = self
+            .variables
+            .iter()
+            .map(|(_, source)| source.cached().map(|value| value as f64))
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        Ok(self.expr.eval(&values)?.round() as i64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::{Checks, SharedConfig};
+
+    // shared sources reading a fixed value from a file, already polled
+    fn registry(sources: &[(&str, i64)]) -> Registry {
+        let mut registry = Registry::new();
+
+        for (name, value) in sources {
+            let path = std::env::temp_dir().join(format!(
+                "kool-expr-{}-{:?}-{}",
+                std::process::id(),
+                std::thread::current().id(),
+                name
+            ));
+            std::fs::write(&path, value.to_string()).unwrap();
+
+            let config = SharedConfig {
+                name: name.to_string(),
+                interval: 1000,
+                src_type: String::from("file"),
+                path: path.to_string_lossy().to_string(),
+                args: None,
+                max_age: None,
+                checks: Checks::default(),
+            };
+
+            let source = SharedSource::new(&config, &registry).unwrap();
+            source.poll().unwrap();
+            registry.insert(name.to_string(), Arc::new(source));
+
+            let _ = std::fs::remove_file(&path);
+        }
+
+        registry
+    }
+
+    fn eval(expression: &str) -> Result<i64, Error> {
+        let registry = registry(&[
+            ("cpu", 70),
+            ("gpu", 50),
+            ("water_in", 30),
+            ("water_out", 40),
+        ]);
+
+        ExprSource::new(expression, &registry)?.get(Duration::from_millis(100))
+    }
+
+    fn rejected(expression: &str) -> String {
+        eval(expression).unwrap_err().message().clone()
+    }
+
+    #[test]
+    fn respects_precedence_and_parentheses() {
+        assert_eq!(eval("2 + 3 * 4").unwrap(), 14);
+        assert_eq!(eval("(2 + 3) * 4").unwrap(), 20);
+        assert_eq!(eval("20 - 8 - 2").unwrap(), 10);
+        assert_eq!(eval("64 / 4 / 2").unwrap(), 8);
+        assert_eq!(eval("0.7 * water_in + 0.3 * water_out").unwrap(), 33);
+    }
+
+    #[test]
+    fn negates() {
+        assert_eq!(eval("-cpu + 100").unwrap(), 30);
+        assert_eq!(eval("--5").unwrap(), 5);
+        assert_eq!(eval("2 * -3").unwrap(), -6);
+        assert_eq!(eval("-(gpu - cpu)").unwrap(), 20);
+    }
+
+    #[test]
+    fn reads_exponents() {
+        assert_eq!(eval("1e3").unwrap(), 1000);
+        assert_eq!(eval("2.5E-1 * 40").unwrap(), 10);
+        assert_eq!(eval("1e+2 + cpu").unwrap(), 170);
+        assert!(rejected("1e").contains("Unexpected trailing input"));
+    }
+
+    #[test]
+    fn calls_functions() {
+        assert_eq!(eval("max(cpu, gpu - 5)").unwrap(), 70);
+        assert_eq!(eval("min(cpu, gpu, 60)").unwrap(), 50);
+        assert_eq!(eval("avg(cpu, gpu)").unwrap(), 60);
+        assert_eq!(eval("max(cpu)").unwrap(), 70);
+        assert_eq!(eval("abs(gpu - cpu)").unwrap(), 20);
+        assert_eq!(eval("clamp(cpu, 0, 60)").unwrap(), 60);
+        assert_eq!(eval("clamp(gpu, 55, 60)").unwrap(), 55);
+    }
+
+    #[test]
+    fn checks_the_number_of_arguments() {
+        for expression in [
+            "max()",
+            "min()",
+            "avg()",
+            "abs()",
+            "abs(cpu, gpu)",
+            "clamp(cpu, 0)",
+        ] {
+            assert!(
+                rejected(expression).contains("Wrong number of arguments"),
+                "{}",
+                expression
+            );
+        }
+
+        assert!(rejected("clamp(cpu, 0, 1, 2)").contains("Wrong number of arguments"));
+        assert!(rejected("median(cpu)").contains("Unknown function median"));
+    }
+
+    #[test]
+    fn reports_unknown_sources() {
+        assert_eq!(
+            rejected("max(cpu, gpuu)"),
+            "Unknown source gpuu in expression max(cpu, gpuu)"
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        assert!(rejected("cpu gpu").contains("Unexpected trailing input"));
+        assert!(rejected("cpu)").contains("Unexpected trailing input"));
+        assert!(rejected("(cpu").contains("Expected )"));
+        assert!(rejected("cpu +").contains("Unexpected end or symbol"));
+        assert!(rejected("cpu % 2").contains("Unexpected character %"));
+        assert!(rejected("1.2.3").contains("Invalid number 1.2.3"));
+    }
+
+    #[test]
+    fn fails_on_division_by_zero() {
+        let err = eval("cpu / (gpu - 50)").unwrap_err();
+
+        assert_eq!(err.code(), Code::InvalidExpression);
+        assert!(err.message().contains("Division by zero"));
+    }
+
+    #[test]
+    fn limits_nesting_and_length() {
+        let nested = |depth: usize| format!("{}cpu{}", "(".repeat(depth), ")".repeat(depth));
+
+        assert_eq!(eval(&nested(MAX_DEPTH - 1)).unwrap(), 70);
+        assert!(rejected(&nested(MAX_DEPTH)).contains("Nested deeper than"));
+        assert!(rejected(&nested(100000)).contains("More than"));
+        assert!(rejected(&"-".repeat(MAX_DEPTH * 2)).contains("Nested deeper than"));
+    }
+}
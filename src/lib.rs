@@ -0,0 +1,95 @@
+//! Fan control for linux, the library behind the kool daemon.
+//!
+//! The types re-exported here are the stable api: an [`AsyncPool`] running
+//! [`Control`]s that read a [`Source`], map the reading through a curve and
+//! write the result to their [`Destination`]s, reporting what they do as
+//! [`Event`]s. Controls are built from a [`Config`], either deserialized from
+//! toml or put together with a [`ControlBuilder`].
+//!
+//! ```
+//! use std::sync::{Arc, Mutex};
+//! use std::time::Duration;
+//! use std::{fs, process, thread};
+//!
+//! use kool::{AsyncPool, Context, ControlBuilder};
+//!
+//! // stand-ins for /sys/class/hwmon/hwmon0/temp1_input and pwm1
+//! let dir = std::env::temp_dir().join(format!("kool-doc-{}", process::id()));
+//! fs::create_dir_all(&dir).unwrap();
+//! let (temp, pwm) = (dir.join("temp1_input"), dir.join("pwm1"));
+//! fs::write(&temp, "60000\n").unwrap();
+//! fs::write(&pwm, "0").unwrap();
+//!
+//! let context = Context::default();
+//!
+//! let control = ControlBuilder::new("cpu")
+//!     .interval(Duration::from_millis(100))
+//!     .source("file", &temp.to_string_lossy())
+//!     .dest(&pwm.to_string_lossy(), 0, 255)
+//!     .points(&[(40000, 20), (80000, 100)])
+//!     .build(&context)
+//!     .unwrap();
+//!
+//! let interval = *control.get_interval();
+//! let control = Arc::new(Mutex::new(control));
+//!
+//! let mut pool = AsyncPool::new(2, Duration::from_millis(50));
+//!
+//! pool.connect_listener(|e| println!("{}", e));
+//! pool.attach_job("cpu", interval, move |emitter| {
+//!     control.lock().unwrap().control(emitter).err()
+//! });
+//!
+//! // a daemon waits forever, the first tick is enough here
+//! thread::sleep(Duration::from_millis(300));
+//! pool.shutdown();
+//! pool.wait();
+//!
+//! assert_eq!(fs::read_to_string(&pwm).unwrap(), "153");
+//! # fs::remove_dir_all(&dir).unwrap();
+//! ```
+//!
+//! The modules hidden from the docs are the parts of the daemon and the
+//! client of the binary, they can change between any releases.
+
+pub mod async_pool;
+pub mod builder;
+pub mod control;
+pub mod destination;
+pub mod emergency;
+pub mod error;
+pub mod event;
+pub mod profile;
+pub mod schedule;
+pub mod signal;
+pub mod source;
+
+mod expr;
+
+#[doc(hidden)]
+pub mod calibrate;
+#[doc(hidden)]
+pub mod client;
+#[doc(hidden)]
+pub mod daemon;
+#[doc(hidden)]
+pub mod logger;
+#[doc(hidden)]
+pub mod metrics;
+#[doc(hidden)]
+pub mod notifier;
+#[doc(hidden)]
+pub mod rules;
+#[doc(hidden)]
+pub mod socket;
+#[doc(hidden)]
+pub mod telemetry;
+
+pub use async_pool::{AsyncPool, Submitter};
+pub use builder::ControlBuilder;
+pub use control::{Config, Context, Control, State, Status};
+pub use destination::{DestConfig, Destination};
+pub use error::{Code, Error};
+pub use event::{Event, Payload, Severity};
+pub use signal::Emitter;
+pub use source::Source;
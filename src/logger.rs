@@ -0,0 +1,348 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    os::unix::net::UnixDatagram,
+    process,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::error::{Code, Error};
+use crate::event::{Event, Payload, Severity};
+
+pub trait Logger: Send + Sync {
+    fn log(&self, event: &Event);
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct LogConfig {
+    // stderr, syslog or journald
+    pub backend: String,
+    // events below this severity are dropped, defaults to info
+    pub level: Option<Severity>,
+    // overrides /dev/log for syslog and /run/systemd/journal/socket for journald
+    pub socket: Option<String>,
+}
+
+pub fn create(config: Option<&LogConfig>) -> Result<Box<dyn Logger>, Error> {
+    let config = match config {
+        Some(config) => config.clone(),
+        None => LogConfig {
+            backend: String::from("stderr"),
+            level: None,
+            socket: None,
+        },
+    };
+
+    let level = config.level.unwrap_or(Severity::Info);
+
+    match config.backend.to_lowercase().as_str() {
+        "stderr" => Ok(Box::new(StderrLogger { level })),
+        "syslog" => Ok(Box::new(SyslogLogger::new(
+            level,
+            config.socket.unwrap_or(String::from("/dev/log")),
+        )?)),
+        "journald" => Ok(Box::new(JournaldLogger::new(
+            level,
+            config
+                .socket
+                .unwrap_or(String::from("/run/systemd/journal/socket")),
+        )?)),
+        backend => Err(Error::new(
+            Code::InvalidLogConfig,
+            format!("Unknown log backend: {}", backend),
+        )),
+    }
+}
+
+fn open_socket() -> Result<UnixDatagram, Error> {
+    UnixDatagram::unbound().map_err(|err| {
+        Error::new(
+            Code::InvalidLogConfig,
+            format!("Cannot create log socket: {}", err),
+        )
+    })
+}
+
+pub struct StderrLogger {
+    level: Severity,
+}
+
+impl Logger for StderrLogger {
+    fn log(&self, event: &Event) {
+        if event.severity >= self.level {
+            eprintln!("{}", event);
+        }
+    }
+}
+
+// RFC 5424 messages over the local syslog socket
+pub struct SyslogLogger {
+    level: Severity,
+    path: String,
+    hostname: String,
+    socket: UnixDatagram,
+}
+
+impl SyslogLogger {
+    // daemon facility
+    const FACILITY: u8 = 3;
+
+    pub fn new(level: Severity, path: String) -> Result<Self, Error> {
+        let hostname = fs::read_to_string("/proc/sys/kernel/hostname")
+            .map(|h| h.trim().to_string())
+            .unwrap_or(String::from("-"));
+
+        Ok(Self {
+            level,
+            path,
+            hostname,
+            socket: open_socket()?,
+        })
+    }
+
+    fn severity(severity: Severity) -> u8 {
+        match severity {
+            Severity::Info => 6,
+            Severity::Warn => 4,
+            Severity::Error => 3,
+            Severity::Critical => 2,
+        }
+    }
+
+    // the characters ", \ and ] have to be escaped in structured data values
+    fn escape(value: &str) -> String {
+        value
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace(']', "\\]")
+    }
+
+    pub fn format(&self, event: &Event) -> String {
+        let priority = SyslogLogger::FACILITY * 8 + SyslogLogger::severity(event.severity);
+
+        let structured_data = match &event.origin {
+            Some(origin) => format!("[kool@32473 control=\"{}\"]", SyslogLogger::escape(origin)),
+            None => String::from("-"),
+        };
+
+        format!(
+            "<{}>1 {} {} kool {} - {} {}",
+            priority,
+            rfc3339(event.timestamp),
+            self.hostname,
+            process::id(),
+            structured_data,
+            event.payload
+        )
+    }
+}
+
+impl Logger for SyslogLogger {
+    fn log(&self, event: &Event) {
+        if event.severity >= self.level {
+            let _ = self
+                .socket
+                .send_to(self.format(event).as_bytes(), &self.path);
+        }
+    }
+}
+
+// native journald protocol, every event is a datagram of KEY=value fields
+pub struct JournaldLogger {
+    level: Severity,
+    path: String,
+    socket: UnixDatagram,
+}
+
+impl JournaldLogger {
+    pub fn new(level: Severity, path: String) -> Result<Self, Error> {
+        Ok(Self {
+            level,
+            path,
+            socket: open_socket()?,
+        })
+    }
+
+    fn field(buffer: &mut Vec<u8>, key: &str, value: &str) {
+        buffer.extend_from_slice(key.as_bytes());
+
+        // values containing new lines are sent with their length instead
+        if value.contains('\n') {
+            buffer.push(b'\n');
+            buffer.extend_from_slice(&(value.len() as u64).to_le_bytes());
+            buffer.extend_from_slice(value.as_bytes());
+        } else {
+            buffer.push(b'=');
+            buffer.extend_from_slice(value.as_bytes());
+        }
+
+        buffer.push(b'\n');
+    }
+
+    pub fn format(&self, event: &Event) -> Vec<u8> {
+        let mut buffer = Vec::new();
+
+        JournaldLogger::field(&mut buffer, "MESSAGE", &event.payload.to_string());
+        JournaldLogger::field(
+            &mut buffer,
+            "PRIORITY",
+            &SyslogLogger::severity(event.severity).to_string(),
+        );
+        JournaldLogger::field(&mut buffer, "SYSLOG_IDENTIFIER", "kool");
+        JournaldLogger::field(&mut buffer, "KOOL_SEVERITY", &event.severity.to_string());
+
+        if let Some(origin) = &event.origin {
+            JournaldLogger::field(&mut buffer, "KOOL_CONTROL", origin);
+        }
+
+        match &event.payload {
+            Payload::Reading { value, label, .. } => {
+                JournaldLogger::field(&mut buffer, "KOOL_READING", &value.to_string());
+
+                if let Some(label) = label {
+                    JournaldLogger::field(&mut buffer, "KOOL_READING_LABEL", label);
+                }
+            }
+            Payload::Output {
+                dest, percent, pwm, ..
+            } => {
+                JournaldLogger::field(&mut buffer, "KOOL_DEST", dest);
+                JournaldLogger::field(&mut buffer, "KOOL_PERCENT", &percent.to_string());
+                JournaldLogger::field(&mut buffer, "KOOL_PWM", &pwm.to_string());
+            }
+            Payload::State { from, to } => {
+                JournaldLogger::field(&mut buffer, "KOOL_STATE_FROM", from);
+                JournaldLogger::field(&mut buffer, "KOOL_STATE", to);
+            }
+            Payload::Error(err) => {
+                JournaldLogger::field(
+                    &mut buffer,
+                    "KOOL_ERROR_CODE",
+                    &(err.code() as usize).to_string(),
+                );
+            }
+            Payload::Log(_) => {}
+        }
+
+        buffer
+    }
+}
+
+impl Logger for JournaldLogger {
+    fn log(&self, event: &Event) {
+        if event.severity >= self.level {
+            let _ = self.socket.send_to(&self.format(event), &self.path);
+        }
+    }
+}
+
+// 2024-01-31T12:00:00.000Z
+pub fn rfc3339(time: SystemTime) -> String {
+    let time = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = time.as_secs();
+
+    // days to civil date, from Howard Hinnant's date algorithms
+    let days = (secs / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        (secs % 86400) / 3600,
+        (secs % 3600) / 60,
+        secs % 60,
+        time.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        path::{Path, PathBuf},
+        time::Duration,
+    };
+
+    // a local datagram socket standing in for /dev/log or the journal
+    fn listen(name: &str) -> (UnixDatagram, PathBuf) {
+        let path = std::env::temp_dir().join(format!("kool-log-{}-{}", process::id(), name));
+        let _ = fs::remove_file(&path);
+
+        let socket = UnixDatagram::bind(&path).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+
+        (socket, path)
+    }
+
+    fn logger(backend: &str, level: Severity, path: &Path) -> Box<dyn Logger> {
+        create(Some(&LogConfig {
+            backend: String::from(backend),
+            level: Some(level),
+            socket: Some(path.to_string_lossy().to_string()),
+        }))
+        .unwrap()
+    }
+
+    fn receive(socket: &UnixDatagram) -> Vec<u8> {
+        let mut buffer = [0; 4096];
+        let size = socket.recv(&mut buffer).unwrap();
+
+        buffer[..size].to_vec()
+    }
+
+    fn event(severity: Severity, message: &str, origin: &str) -> Event {
+        let mut event = Event::new(severity, Payload::Log(message.to_string())).with_origin(origin);
+        event.timestamp = UNIX_EPOCH + Duration::from_millis(1706702400123);
+        event
+    }
+
+    #[test]
+    fn syslog_sends_rfc5424_with_escaped_structured_data() {
+        let (socket, path) = listen("syslog");
+        let logger = logger("syslog", Severity::Info, &path);
+
+        logger.log(&event(Severity::Error, "pump failed", "cpu \"a\\b]"));
+
+        let message = String::from_utf8(receive(&socket)).unwrap();
+
+        assert!(message.starts_with("<27>1 2024-01-31T12:00:00.123Z "));
+        assert!(message.contains(&format!(" kool {} - ", process::id())));
+        assert!(message.ends_with(" [kool@32473 control=\"cpu \\\"a\\\\b\\]\"] pump failed"));
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn journald_sends_fields_and_length_prefixes_multiline_values() {
+        let (socket, path) = listen("journald");
+        let logger = logger("journald", Severity::Warn, &path);
+
+        // below the level, nothing is sent
+        logger.log(&event(Severity::Info, "ignored", "cpu"));
+        logger.log(&event(Severity::Critical, "line one\nline two", "cpu"));
+
+        let mut expected = b"MESSAGE\n".to_vec();
+        expected.extend_from_slice(&17u64.to_le_bytes());
+        expected.extend_from_slice(b"line one\nline two\n");
+        expected.extend_from_slice(b"PRIORITY=2\n");
+        expected.extend_from_slice(b"SYSLOG_IDENTIFIER=kool\n");
+        expected.extend_from_slice(b"KOOL_SEVERITY=critical\n");
+        expected.extend_from_slice(b"KOOL_CONTROL=cpu\n");
+
+        assert_eq!(receive(&socket), expected);
+
+        let _ = fs::remove_file(&path);
+    }
+}
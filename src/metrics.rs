@@ -0,0 +1,391 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    fs,
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use crate::async_pool::Histogram;
+use crate::error::{Code, Error};
+use crate::event::{Event, Payload};
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct MetricsConfig {
+    // address of the http listener serving /metrics, e.g. 127.0.0.1:9101
+    pub listen: Option<String>,
+    // file for the node_exporter textfile collector, should end in .prom
+    pub textfile: Option<String>,
+    // in milliseconds, how often the textfile is rewritten, defaults to 1000
+    pub textfile_interval: Option<u64>,
+}
+
+struct Output {
+    pwm: i64,
+    rpm: Option<i64>,
+}
+
+#[derive(Default)]
+struct State {
+    temperature: BTreeMap<String, i64>,
+    // before the plausibility checks
+    raw: BTreeMap<String, i64>,
+    target: BTreeMap<String, f64>,
+    // keyed by control and destination
+    outputs: BTreeMap<(String, String), Output>,
+    // keyed by control and error code
+    errors: BTreeMap<(String, String), u64>,
+}
+
+// collects the latest values of every control, hooked in as a signal listener
+pub struct Metrics {
+    state: Mutex<State>,
+    lateness: Arc<Mutex<Histogram>>,
+}
+
+// backslashes, quotes and new lines have to be escaped in label values
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+impl Metrics {
+    pub fn new(lateness: Arc<Mutex<Histogram>>) -> Self {
+        Self {
+            state: Mutex::new(State::default()),
+            lateness,
+        }
+    }
+
+    pub fn record(&self, event: &Event) {
+        let control = event.origin.clone().unwrap_or_default();
+        let mut state = self.state.lock().unwrap();
+
+        match &event.payload {
+            Payload::Reading { raw, value, .. } => {
+                state.raw.insert(control.clone(), *raw);
+                state.temperature.insert(control, *value);
+            }
+            Payload::Output {
+                dest,
+                target,
+                pwm,
+                rpm,
+                ..
+            } => {
+                state.target.insert(control.clone(), *target);
+                state.outputs.insert(
+                    (control, dest.clone()),
+                    Output {
+                        pwm: *pwm,
+                        rpm: *rpm,
+                    },
+                );
+            }
+            Payload::Error(err) => {
+                *state
+                    .errors
+                    .entry((control, format!("{:?}", err.code())))
+                    .or_insert(0) += 1;
+            }
+            _ => {}
+        }
+    }
+
+    // prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        {
+            let state = self.state.lock().unwrap();
+
+            let _ = writeln!(
+                out,
+                "# HELP kool_temperature Last reading of the control source."
+            );
+            let _ = writeln!(out, "# TYPE kool_temperature gauge");
+            for (control, value) in state.temperature.iter() {
+                let _ = writeln!(
+                    out,
+                    "kool_temperature{{control=\"{}\"}} {}",
+                    escape(control),
+                    value
+                );
+            }
+
+            let _ = writeln!(
+                out,
+                "# HELP kool_raw_reading Last reading of the control source before the checks."
+            );
+            let _ = writeln!(out, "# TYPE kool_raw_reading gauge");
+            for (control, raw) in state.raw.iter() {
+                let _ = writeln!(
+                    out,
+                    "kool_raw_reading{{control=\"{}\"}} {}",
+                    escape(control),
+                    raw
+                );
+            }
+
+            let _ = writeln!(
+                out,
+                "# HELP kool_target_percent Output of the control curve."
+            );
+            let _ = writeln!(out, "# TYPE kool_target_percent gauge");
+            for (control, target) in state.target.iter() {
+                let _ = writeln!(
+                    out,
+                    "kool_target_percent{{control=\"{}\"}} {}",
+                    escape(control),
+                    target
+                );
+            }
+
+            let _ = writeln!(
+                out,
+                "# HELP kool_pwm Last value written to the destination."
+            );
+            let _ = writeln!(out, "# TYPE kool_pwm gauge");
+            for ((control, dest), output) in state.outputs.iter() {
+                let _ = writeln!(
+                    out,
+                    "kool_pwm{{control=\"{}\",dest=\"{}\"}} {}",
+                    escape(control),
+                    escape(dest),
+                    output.pwm
+                );
+            }
+
+            let _ = writeln!(out, "# HELP kool_rpm Fan speed of the destination.");
+            let _ = writeln!(out, "# TYPE kool_rpm gauge");
+            for ((control, dest), output) in state.outputs.iter() {
+                if let Some(rpm) = output.rpm {
+                    let _ = writeln!(
+                        out,
+                        "kool_rpm{{control=\"{}\",dest=\"{}\"}} {}",
+                        escape(control),
+                        escape(dest),
+                        rpm
+                    );
+                }
+            }
+
+            let _ = writeln!(out, "# HELP kool_errors_total Errors by control and code.");
+            let _ = writeln!(out, "# TYPE kool_errors_total counter");
+            for ((control, code), count) in state.errors.iter() {
+                let _ = writeln!(
+                    out,
+                    "kool_errors_total{{control=\"{}\",code=\"{}\"}} {}",
+                    escape(control),
+                    code,
+                    count
+                );
+            }
+        }
+
+        let lateness = self.lateness.lock().unwrap();
+
+        let _ = writeln!(
+            out,
+            "# HELP kool_job_lateness_seconds How late the jobs ran after their interval."
+        );
+        let _ = writeln!(out, "# TYPE kool_job_lateness_seconds histogram");
+        for (bound, count) in lateness.bounds.iter().zip(lateness.counts.iter()) {
+            let _ = writeln!(
+                out,
+                "kool_job_lateness_seconds_bucket{{le=\"{}\"}} {}",
+                bound, count
+            );
+        }
+        let _ = writeln!(
+            out,
+            "kool_job_lateness_seconds_bucket{{le=\"+Inf\"}} {}",
+            lateness.count
+        );
+        let _ = writeln!(out, "kool_job_lateness_seconds_sum {}", lateness.sum);
+        let _ = writeln!(out, "kool_job_lateness_seconds_count {}", lateness.count);
+
+        out
+    }
+
+    // written next to the target and renamed, so the collector never reads half a file
+    pub fn write_textfile(&self, path: &str) -> Result<(), Error> {
+        let tmp = format!("{}.tmp", path);
+
+        fs::write(&tmp, self.render())
+            .and_then(|_| fs::rename(&tmp, path))
+            .map_err(|err| {
+                Error::new(
+                    Code::UnableToWrite,
+                    format!("Cannot write metrics to {}: {}", path, err),
+                )
+            })
+    }
+
+    // binds right away so a bad address fails at startup, connections are served on a thread,
+    // returns the bound address for port 0
+    pub fn serve(metrics: Arc<Metrics>, address: &str) -> Result<SocketAddr, Error> {
+        let invalid = |err: std::io::Error| {
+            Error::new(
+                Code::InvalidMetricsConfig,
+                format!("Cannot listen on {}: {}", address, err),
+            )
+        };
+
+        let listener = TcpListener::bind(address).map_err(invalid)?;
+        let bound = listener.local_addr().map_err(invalid)?;
+
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let _ = Metrics::respond(&metrics, stream);
+            }
+        });
+
+        Ok(bound)
+    }
+
+    fn respond(metrics: &Metrics, mut stream: TcpStream) -> std::io::Result<()> {
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut request = String::new();
+        reader.read_line(&mut request)?;
+
+        // skip the headers, nothing in them matters
+        let mut header = String::new();
+        while reader.read_line(&mut header)? > 0 && header.trim() != "" {
+            header.clear();
+        }
+
+        let mut parts = request.split_whitespace();
+
+        let (status, content_type, body) = match (parts.next(), parts.next()) {
+            (Some("GET"), Some("/metrics")) => {
+                ("200 OK", "text/plain; version=0.0.4", metrics.render())
+            }
+            _ => ("404 Not Found", "text/plain", String::from("Not Found\n")),
+        };
+
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            content_type,
+            body.len(),
+            body
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use crate::event::Severity;
+    use std::{io::Read, process};
+
+    fn metrics() -> Arc<Metrics> {
+        let lateness = Histogram {
+            bounds: vec![0.01, 0.1],
+            counts: vec![1, 2],
+            sum: 0.05,
+            count: 3,
+        };
+
+        let metrics = Metrics::new(Arc::new(Mutex::new(lateness)));
+        let control = "cpu \"fan\"\\1\n";
+
+        metrics.record(
+            &Event::new(
+                Severity::Info,
+                Payload::Reading {
+                    raw: 48,
+                    value: 47,
+                    label: None,
+                },
+            )
+            .with_origin(control),
+        );
+        metrics.record(
+            &Event::new(
+                Severity::Info,
+                Payload::Output {
+                    dest: String::from("/sys/pwm1"),
+                    target: 40.0,
+                    percent: 40.0,
+                    pwm: 102,
+                    rpm: Some(900),
+                },
+            )
+            .with_origin(control),
+        );
+        metrics.record(
+            &Event::error(Error::new(Code::FanStall, String::from("stalled"))).with_origin(control),
+        );
+
+        Arc::new(metrics)
+    }
+
+    fn get(address: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        response
+    }
+
+    #[test]
+    fn serves_metrics_with_escaped_labels() {
+        let address = Metrics::serve(metrics(), "127.0.0.1:0").unwrap();
+        let response = get(address, "/metrics");
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Type: text/plain; version=0.0.4\r\n"));
+
+        let body = response.split("\r\n\r\n").nth(1).unwrap();
+        let label = "control=\"cpu \\\"fan\\\"\\\\1\\n\"";
+
+        assert!(body.contains(&format!("kool_temperature{{{}}} 47\n", label)));
+        assert!(body.contains(&format!("kool_raw_reading{{{}}} 48\n", label)));
+        assert!(body.contains(&format!("kool_target_percent{{{}}} 40\n", label)));
+        assert!(body.contains(&format!("kool_pwm{{{},dest=\"/sys/pwm1\"}} 102\n", label)));
+        assert!(body.contains(&format!("kool_rpm{{{},dest=\"/sys/pwm1\"}} 900\n", label)));
+        assert!(body.contains(&format!(
+            "kool_errors_total{{{},code=\"FanStall\"}} 1\n",
+            label
+        )));
+        assert!(body.contains("kool_job_lateness_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(response.contains(&format!("Content-Length: {}\r\n", body.len())));
+    }
+
+    #[test]
+    fn answers_other_paths_with_not_found() {
+        let address = Metrics::serve(metrics(), "127.0.0.1:0").unwrap();
+
+        assert!(get(address, "/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(get(address, "/metrics/x").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[test]
+    fn replaces_the_textfile_as_a_whole() {
+        let path = std::env::temp_dir().join(format!("kool-metrics-{}.prom", process::id()));
+        let path = path.to_string_lossy().to_string();
+        let metrics = metrics();
+
+        fs::write(&path, "stale").unwrap();
+        metrics.write_textfile(&path).unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), metrics.render());
+        assert!(fs::metadata(format!("{}.tmp", path)).is_err());
+
+        let _ = fs::remove_file(&path);
+    }
+}
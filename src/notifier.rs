@@ -0,0 +1,503 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::OpenOptions,
+    io::Write,
+    process::Command,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::error::{Code, Error};
+use crate::event::Severity;
+
+pub trait Notifier: Send + Sync {
+    fn notify(&self, severity: Severity, summary: &str, body: &str);
+}
+
+#[cfg(feature = "desktop")]
+pub struct DesktopNotifier {}
+
+#[cfg(feature = "desktop")]
+impl Notifier for DesktopNotifier {
+    fn notify(&self, severity: Severity, summary: &str, body: &str) {
+        let urgency = match severity {
+            Severity::Info | Severity::Warn => notify_rust::Urgency::Low,
+            Severity::Error => notify_rust::Urgency::Normal,
+            Severity::Critical => notify_rust::Urgency::Critical,
+        };
+
+        let _ = notify_rust::Notification::new()
+            .summary(summary)
+            .body(body)
+            .appname("kool")
+            .urgency(urgency)
+            .show();
+    }
+}
+
+// runs a command with the severity, summary and body appended to the arguments,
+// e.g. a wrapper around mail or ntfy
+pub struct CommandNotifier {
+    command: String,
+    args: Vec<String>,
+}
+
+impl Notifier for CommandNotifier {
+    fn notify(&self, severity: Severity, summary: &str, body: &str) {
+        let child = Command::new(&self.command)
+            .args(&self.args)
+            .arg(severity.to_string())
+            .arg(summary)
+            .arg(body)
+            .spawn();
+
+        // don't hold up the listener, but don't leave zombies behind either
+        if let Ok(mut child) = child {
+            thread::spawn(move || child.wait());
+        }
+    }
+}
+
+pub struct FileNotifier {
+    path: String,
+}
+
+impl Notifier for FileNotifier {
+    fn notify(&self, severity: Severity, summary: &str, body: &str) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|t| t.as_secs())
+            .unwrap_or(0);
+
+        if let Ok(mut file) = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+        {
+            let _ = writeln!(file, "{} {} {}: {}", timestamp, severity, summary, body);
+        }
+    }
+}
+
+pub struct StderrNotifier {}
+
+impl Notifier for StderrNotifier {
+    fn notify(&self, severity: Severity, summary: &str, body: &str) {
+        eprintln!("{} {}: {}", severity, summary, body);
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct NotifierConfig {
+    // desktop, command, file or stderr
+    pub kind: String,
+    // for file
+    pub path: Option<String>,
+    // for command
+    pub command: Option<String>,
+    pub args: Option<Vec<String>>,
+    // only events with these severities are sent, otherwise everything from min_severity up
+    pub severities: Option<Vec<Severity>>,
+    // defaults to error
+    pub min_severity: Option<Severity>,
+}
+
+impl NotifierConfig {
+    fn accepts(&self, severity: Severity) -> bool {
+        match &self.severities {
+            Some(severities) => severities.contains(&severity),
+            None => severity >= self.min_severity.unwrap_or(Severity::Error),
+        }
+    }
+}
+
+pub struct Notifiers {
+    backends: Vec<(Box<dyn Notifier>, NotifierConfig)>,
+}
+
+impl Notifiers {
+    pub fn from_configs(configs: Option<&Vec<NotifierConfig>>) -> Result<Self, Error> {
+        let configs = match configs {
+            Some(configs) => configs.clone(),
+            None => vec![NotifierConfig {
+                kind: String::from(if cfg!(feature = "desktop") {
+                    "desktop"
+                } else {
+                    "stderr"
+                }),
+                path: None,
+                command: None,
+                args: None,
+                severities: None,
+                min_severity: None,
+            }],
+        };
+
+        let mut backends = Vec::new();
+
+        for config in configs {
+            backends.push((Notifiers::create(&config)?, config));
+        }
+
+        Ok(Self { backends })
+    }
+
+    fn create(config: &NotifierConfig) -> Result<Box<dyn Notifier>, Error> {
+        let missing = |field: &str| {
+            Error::new(
+                Code::InvalidNotifierConfig,
+                format!("Notifier {} requires {}", config.kind, field),
+            )
+        };
+
+        match config.kind.to_lowercase().as_str() {
+            #[cfg(feature = "desktop")]
+            "desktop" => Ok(Box::new(DesktopNotifier {})),
+            "command" => Ok(Box::new(CommandNotifier {
+                command: config.command.clone().ok_or_else(|| missing("command"))?,
+                args: config.args.clone().unwrap_or_default(),
+            })),
+            "file" => Ok(Box::new(FileNotifier {
+                path: config.path.clone().ok_or_else(|| missing("path"))?,
+            })),
+            "stderr" => Ok(Box::new(StderrNotifier {})),
+            kind => Err(Error::new(
+                Code::InvalidNotifierConfig,
+                format!("Unknown or disabled notifier: {}", kind),
+            )),
+        }
+    }
+
+    pub fn notify(&self, severity: Severity, summary: &str, body: &str) {
+        for (notifier, config) in self.backends.iter() {
+            if config.accepts(severity) {
+                notifier.notify(severity, summary, body);
+            }
+        }
+    }
+}
+
+struct Suppressed {
+    severity: Severity,
+    summary: String,
+    // of the last failure
+    message: String,
+    // start of the current window
+    since: Instant,
+    // failures not sent since the start of the window
+    count: u32,
+    healthy: bool,
+    // only the first recovery of a window is sent
+    recovery_sent: bool,
+}
+
+// the first failure of a control with a given code is sent right away, repeats are
+// summed up at the end of every window and the first recovery is sent right away too,
+// so a flapping control gets a failure, a recovery and a summary per window,
+// critical failures are never held back
+pub struct Throttle {
+    notifiers: Arc<Notifiers>,
+    window: Duration,
+    failures: Mutex<HashMap<(String, Code), Suppressed>>,
+    clock: Box<dyn Fn() -> Instant + Send + Sync>,
+}
+
+impl Throttle {
+    pub fn new(notifiers: Arc<Notifiers>, window: Duration) -> Self {
+        Throttle::with_clock(notifiers, window, Box::new(Instant::now))
+    }
+
+    pub fn with_clock(
+        notifiers: Arc<Notifiers>,
+        window: Duration,
+        clock: Box<dyn Fn() -> Instant + Send + Sync>,
+    ) -> Self {
+        Self {
+            notifiers,
+            window,
+            failures: Mutex::new(HashMap::new()),
+            clock,
+        }
+    }
+
+    fn window_text(&self) -> String {
+        let secs = self.window.as_secs();
+
+        if secs >= 60 && secs % 60 == 0 {
+            format!("{} minutes", secs / 60)
+        } else {
+            format!("{} seconds", secs)
+        }
+    }
+
+    // sends the failures held back so far
+    fn summarize(&self, origin: &str, suppressed: &mut Suppressed) {
+        if suppressed.count == 0 {
+            return;
+        }
+
+        self.notifiers.notify(
+            suppressed.severity,
+            &suppressed.summary,
+            &format!(
+                "{} failed {} more times in the last {}: {}",
+                origin,
+                suppressed.count,
+                self.window_text(),
+                suppressed.message
+            ),
+        );
+
+        suppressed.count = 0;
+    }
+
+    pub fn failure(&self, origin: &str, severity: Severity, summary: &str, err: &Error) {
+        let now = (self.clock)();
+        let mut failures = self.failures.lock().unwrap();
+
+        let suppressed = match failures.get_mut(&(origin.to_string(), err.code())) {
+            Some(suppressed) if severity < Severity::Critical => suppressed,
+            _ => {
+                failures.insert(
+                    (origin.to_string(), err.code()),
+                    Suppressed {
+                        severity,
+                        summary: summary.to_string(),
+                        message: err.message().clone(),
+                        since: now,
+                        count: 0,
+                        healthy: false,
+                        recovery_sent: false,
+                    },
+                );
+
+                self.notifiers.notify(severity, summary, err.message());
+                return;
+            }
+        };
+
+        suppressed.severity = suppressed.severity.max(severity);
+        suppressed.message = err.message().clone();
+        suppressed.count += 1;
+        suppressed.healthy = false;
+    }
+
+    pub fn success(&self, origin: &str) {
+        let mut failures = self.failures.lock().unwrap();
+
+        let mut recovered: Option<Severity> = None;
+
+        for ((failed, _), suppressed) in failures.iter_mut() {
+            if failed != origin || suppressed.healthy {
+                continue;
+            }
+
+            suppressed.healthy = true;
+
+            if !suppressed.recovery_sent {
+                self.summarize(origin, suppressed);
+                suppressed.recovery_sent = true;
+                recovered = recovered.max(Some(suppressed.severity));
+            }
+        }
+
+        // sent with the same severity so it reaches everyone who got the failure
+        if let Some(severity) = recovered {
+            self.notifiers
+                .notify(severity, "Kool Recovered", &format!("{} recovered", origin));
+        }
+    }
+
+    // called periodically, sends the summaries of the windows that ended and
+    // forgets the controls that stayed healthy for a whole window
+    pub fn flush(&self) {
+        let now = (self.clock)();
+        let mut failures = self.failures.lock().unwrap();
+
+        failures.retain(|(origin, _), suppressed| {
+            if now.duration_since(suppressed.since) < self.window {
+                return true;
+            }
+
+            let quiet = suppressed.count == 0;
+
+            self.summarize(origin, suppressed);
+            suppressed.since = now;
+            suppressed.recovery_sent = false;
+
+            !(quiet && suppressed.healthy)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Sent = Arc<Mutex<Vec<(Severity, String, String)>>>;
+
+    struct RecordingNotifier {
+        sent: Sent,
+    }
+
+    impl Notifier for RecordingNotifier {
+        fn notify(&self, severity: Severity, summary: &str, body: &str) {
+            self.sent
+                .lock()
+                .unwrap()
+                .push((severity, summary.to_string(), body.to_string()));
+        }
+    }
+
+    struct Fixture {
+        throttle: Throttle,
+        sent: Sent,
+        now: Arc<Mutex<Instant>>,
+    }
+
+    impl Fixture {
+        fn new() -> Self {
+            let sent = Sent::default();
+            let now = Arc::new(Mutex::new(Instant::now()));
+
+            let notifiers = Notifiers {
+                backends: vec![(
+                    Box::new(RecordingNotifier {
+                        sent: Arc::clone(&sent),
+                    }),
+                    NotifierConfig {
+                        kind: String::from("recording"),
+                        path: None,
+                        command: None,
+                        args: None,
+                        severities: None,
+                        min_severity: Some(Severity::Info),
+                    },
+                )],
+            };
+
+            let clock = Arc::clone(&now);
+            let throttle = Throttle::with_clock(
+                Arc::new(notifiers),
+                Duration::from_secs(300),
+                Box::new(move || *clock.lock().unwrap()),
+            );
+
+            Self {
+                throttle,
+                sent,
+                now,
+            }
+        }
+
+        fn advance(&self, secs: u64) {
+            *self.now.lock().unwrap() += Duration::from_secs(secs);
+            self.throttle.flush();
+        }
+
+        fn fail(&self, severity: Severity) {
+            let err = Error::new(Code::FanStall, String::from("Fan stalled"));
+            self.throttle.failure("cpu", severity, "Kool Failure", &err);
+        }
+
+        // bodies sent since the last call
+        fn take(&self) -> Vec<String> {
+            self.sent
+                .lock()
+                .unwrap()
+                .drain(..)
+                .map(|(_, _, body)| body)
+                .collect()
+        }
+    }
+
+    #[test]
+    fn sends_the_summary_when_the_window_ends() {
+        let fixture = Fixture::new();
+
+        fixture.fail(Severity::Error);
+        assert_eq!(fixture.take(), vec!["Fan stalled"]);
+
+        fixture.advance(10);
+        fixture.fail(Severity::Error);
+        fixture.fail(Severity::Error);
+        assert!(fixture.take().is_empty());
+
+        // no failure arrives after the window, the count still goes out
+        fixture.advance(290);
+        assert_eq!(
+            fixture.take(),
+            vec!["cpu failed 2 more times in the last 5 minutes: Fan stalled"]
+        );
+
+        fixture.advance(300);
+        assert!(fixture.take().is_empty());
+    }
+
+    #[test]
+    fn sends_the_summary_and_the_recovery_right_away() {
+        let fixture = Fixture::new();
+
+        fixture.fail(Severity::Error);
+        fixture.fail(Severity::Error);
+        fixture.take();
+
+        fixture.advance(5);
+        fixture.throttle.success("cpu");
+        assert_eq!(
+            fixture.take(),
+            vec![
+                "cpu failed 1 more times in the last 5 minutes: Fan stalled",
+                "cpu recovered"
+            ]
+        );
+
+        fixture.throttle.success("cpu");
+        assert!(fixture.take().is_empty());
+    }
+
+    #[test]
+    fn sends_a_flapping_control_once_per_window() {
+        let fixture = Fixture::new();
+
+        for _ in 0..5 {
+            fixture.fail(Severity::Error);
+            fixture.throttle.success("cpu");
+            fixture.advance(10);
+        }
+
+        assert_eq!(fixture.take(), vec!["Fan stalled", "cpu recovered"]);
+
+        fixture.advance(250);
+        assert_eq!(
+            fixture.take(),
+            vec!["cpu failed 4 more times in the last 5 minutes: Fan stalled"]
+        );
+
+        // healthy for a whole window, a new failure is sent right away again
+        fixture.advance(300);
+        fixture.fail(Severity::Error);
+        assert_eq!(fixture.take(), vec!["Fan stalled"]);
+    }
+
+    #[test]
+    fn never_holds_back_critical_failures() {
+        let fixture = Fixture::new();
+
+        fixture.fail(Severity::Critical);
+        fixture.advance(1);
+        fixture.fail(Severity::Critical);
+        fixture.fail(Severity::Critical);
+
+        assert_eq!(
+            fixture.take(),
+            vec!["Fan stalled", "Fan stalled", "Fan stalled"]
+        );
+
+        fixture.throttle.success("cpu");
+        assert_eq!(fixture.sent.lock().unwrap()[0].0, Severity::Critical);
+        assert_eq!(fixture.take(), vec!["cpu recovered"]);
+    }
+}
@@ -0,0 +1,203 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+    time::SystemTime,
+};
+
+use crate::control::{Config, Curve};
+use crate::error::{Code, Error};
+
+// the part of a control a profile replaces, missing fields keep the value of the control
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct CurveConfig {
+    // name of the control
+    pub name: String,
+    pub curve: Option<String>,
+    pub points: Option<Vec<Vec<i64>>>,
+    pub min_start_percent: Option<f64>,
+    pub min_run_percent: Option<f64>,
+    pub stop_below: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct ProfileConfig {
+    pub name: String,
+    pub control: Vec<CurveConfig>,
+}
+
+pub struct Active {
+    // none runs the curves of the controls themselves
+    pub name: Option<String>,
+    controls: HashMap<String, CurveConfig>,
+}
+
+impl Active {
+    pub fn get(&self, control: &str) -> Option<&CurveConfig> {
+        self.controls.get(control)
+    }
+}
+
+// the active profile is swapped as a whole, every control picks it up on its next tick
+pub struct Profiles {
+    profiles: Vec<ProfileConfig>,
+    active: RwLock<Arc<Active>>,
+}
+
+static CYCLE_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sigusr1(_: libc::c_int) {
+    CYCLE_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+impl Profiles {
+    pub fn new(
+        profiles: Vec<ProfileConfig>,
+        controls: &[Config],
+        default: Option<&String>,
+    ) -> Result<Self, Error> {
+        for profile in profiles.iter() {
+            for curve in profile.control.iter() {
+                let control = controls
+                    .iter()
+                    .find(|control| control.name == curve.name)
+                    .ok_or_else(|| {
+                        Error::new(
+                            Code::InvalidProfileConfig,
+                            format!(
+                                "Profile {} has unknown control {}",
+                                profile.name, curve.name
+                            ),
+                        )
+                    })?;
+
+                Curve::new(control, Some(curve)).map_err(|err| {
+                    Error::new(
+                        Code::InvalidProfileConfig,
+                        format!("Profile {}: {}", profile.name, err),
+                    )
+                })?;
+            }
+        }
+
+        let profiles = Self {
+            profiles,
+            active: RwLock::new(Arc::new(Active {
+                name: None,
+                controls: HashMap::new(),
+            })),
+        };
+
+        if let Some(default) = default {
+            profiles.switch(default)?;
+        }
+
+        Ok(profiles)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.profiles.iter().any(|profile| profile.name == name)
+    }
+
+    pub fn active(&self) -> Arc<Active> {
+        Arc::clone(&self.active.read().unwrap())
+    }
+
+    pub fn switch(&self, name: &str) -> Result<(), Error> {
+        let profile = self
+            .profiles
+            .iter()
+            .find(|profile| profile.name == name)
+            .ok_or_else(|| {
+                Error::new(
+                    Code::InvalidProfileConfig,
+                    format!("Unknown profile: {}", name),
+                )
+            })?;
+
+        let active = Active {
+            name: Some(profile.name.clone()),
+            controls: profile
+                .control
+                .iter()
+                .map(|curve| (curve.name.clone(), curve.clone()))
+                .collect(),
+        };
+
+        *self.active.write().unwrap() = Arc::new(active);
+
+        Ok(())
+    }
+
+    // back to the curves of the controls themselves
+    pub fn reset(&self) {
+        *self.active.write().unwrap() = Arc::new(Active {
+            name: None,
+            controls: HashMap::new(),
+        });
+    }
+
+    // SIGUSR1 moves on to the next profile, checked by cycle_requested
+    pub fn handle_sigusr1() {
+        unsafe {
+            libc::signal(libc::SIGUSR1, on_sigusr1 as *const () as libc::sighandler_t);
+        }
+    }
+
+    // returns the profile switched to
+    pub fn cycle_requested(&self) -> Option<Result<String, Error>> {
+        if !CYCLE_REQUESTED.swap(false, Ordering::SeqCst) || self.profiles.is_empty() {
+            return None;
+        }
+
+        let current = self.active().name.clone();
+        let next = match self
+            .profiles
+            .iter()
+            .position(|profile| Some(&profile.name) == current.as_ref())
+        {
+            Some(idx) => &self.profiles[(idx + 1) % self.profiles.len()],
+            None => &self.profiles[0],
+        };
+
+        Some(self.switch(&next.name).map(|_| next.name.clone()))
+    }
+}
+
+// switches to the profile named in a file whenever the file changes
+pub struct ProfileFile {
+    path: String,
+    modified: Option<SystemTime>,
+}
+
+impl ProfileFile {
+    pub fn new(path: String) -> Self {
+        Self {
+            path,
+            modified: None,
+        }
+    }
+
+    // returns the profile switched to
+    pub fn check(&mut self, profiles: &Profiles) -> Option<Result<String, Error>> {
+        let modified = fs::metadata(&self.path).and_then(|m| m.modified()).ok()?;
+
+        if self.modified == Some(modified) {
+            return None;
+        }
+
+        self.modified = Some(modified);
+
+        let name = fs::read_to_string(&self.path).ok()?.trim().to_string();
+
+        if name.is_empty() || profiles.active().name.as_ref() == Some(&name) {
+            return None;
+        }
+
+        Some(profiles.switch(&name).map(|_| name))
+    }
+}
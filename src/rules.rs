@@ -0,0 +1,398 @@
+use serde::{Deserialize, Serialize};
+use std::{fmt, fs};
+
+use crate::error::{Code, Error};
+use crate::profile::Profiles;
+
+// something about the machine a rule can depend on
+pub trait Condition: Send + fmt::Display {
+    fn holds(&self) -> bool;
+}
+
+// ac when any power supply reports online, batteries don't have the file
+pub struct PowerCondition {
+    root: String,
+    ac: bool,
+}
+
+impl PowerCondition {
+    fn on_ac(&self) -> bool {
+        let entries = match fs::read_dir(&self.root) {
+            Ok(entries) => entries,
+            // desktops without power supplies are always on ac
+            Err(_) => return true,
+        };
+
+        entries.flatten().any(|entry| {
+            fs::read_to_string(entry.path().join("online"))
+                .map(|online| online.trim() == "1")
+                .unwrap_or(false)
+        })
+    }
+}
+
+impl Condition for PowerCondition {
+    fn holds(&self) -> bool {
+        self.on_ac() == self.ac
+    }
+}
+
+impl fmt::Display for PowerCondition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "on {}", if self.ac { "ac" } else { "battery" })
+    }
+}
+
+// matches the name in /proc/<pid>/comm
+pub struct ProcessCondition {
+    root: String,
+    name: String,
+}
+
+impl Condition for ProcessCondition {
+    fn holds(&self) -> bool {
+        let entries = match fs::read_dir(&self.root) {
+            Ok(entries) => entries,
+            Err(_) => return false,
+        };
+
+        entries
+            .flatten()
+            .filter(|entry| entry.file_name().to_string_lossy().parse::<u32>().is_ok())
+            .any(|entry| {
+                fs::read_to_string(entry.path().join("comm"))
+                    .map(|comm| comm.trim() == self.name)
+                    .unwrap_or(false)
+            })
+    }
+}
+
+impl fmt::Display for ProcessCondition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "while {} is running", self.name)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct RuleConfig {
+    pub profile: String,
+    // ac or battery
+    pub power: Option<String>,
+    // name of a running process
+    pub process: Option<String>,
+    // overrides /sys/class/power_supply for power and /proc for process
+    pub root: Option<String>,
+}
+
+pub struct Rule {
+    profile: String,
+    // all of them have to hold
+    conditions: Vec<Box<dyn Condition>>,
+}
+
+impl Rule {
+    pub fn new(profile: String, conditions: Vec<Box<dyn Condition>>) -> Self {
+        Self {
+            profile,
+            conditions,
+        }
+    }
+
+    pub fn from_config(config: &RuleConfig) -> Result<Self, Error> {
+        let mut conditions: Vec<Box<dyn Condition>> = Vec::new();
+
+        if let Some(power) = &config.power {
+            let ac = match power.as_str() {
+                "ac" => true,
+                "battery" => false,
+                power => {
+                    return Err(Error::new(
+                        Code::InvalidRuleConfig,
+                        format!("Unknown power state {}, expected ac or battery", power),
+                    ))
+                }
+            };
+
+            conditions.push(Box::new(PowerCondition {
+                root: config
+                    .root
+                    .clone()
+                    .unwrap_or(String::from("/sys/class/power_supply")),
+                ac,
+            }));
+        }
+
+        if let Some(process) = &config.process {
+            conditions.push(Box::new(ProcessCondition {
+                root: config.root.clone().unwrap_or(String::from("/proc")),
+                name: process.clone(),
+            }));
+        }
+
+        if conditions.is_empty() {
+            return Err(Error::new(
+                Code::InvalidRuleConfig,
+                format!("Rule for profile {} has no condition", config.profile),
+            ));
+        }
+
+        Ok(Rule::new(config.profile.clone(), conditions))
+    }
+
+    fn holds(&self) -> bool {
+        self.conditions.iter().all(|condition| condition.holds())
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let conditions = self
+            .conditions
+            .iter()
+            .map(|condition| condition.to_string())
+            .collect::<Vec<_>>();
+
+        write!(f, "{} {}", self.profile, conditions.join(" and "))
+    }
+}
+
+// the first rule that holds picks the profile, but only when that changes,
+// so a profile switched by hand stays until the machine changes state,
+// once no rule holds any more the profile from before the rules goes back
+pub struct Rules {
+    rules: Vec<Rule>,
+    matched: Option<usize>,
+    // the profile active before a rule took over
+    previous: Option<String>,
+}
+
+impl Rules {
+    pub fn new(rules: Vec<Rule>, profiles: &Profiles) -> Result<Self, Error> {
+        for rule in rules.iter() {
+            if !profiles.contains(&rule.profile) {
+                return Err(Error::new(
+                    Code::InvalidRuleConfig,
+                    format!("Rule {} has unknown profile", rule),
+                ));
+            }
+        }
+
+        Ok(Self {
+            rules,
+            matched: None,
+            previous: None,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    // returns what the profile was switched to
+    pub fn apply(&mut self, profiles: &Profiles) -> Option<Result<String, Error>> {
+        let matched = self.rules.iter().position(|rule| rule.holds());
+
+        if matched == self.matched {
+            return None;
+        }
+
+        if self.matched.is_none() {
+            self.previous = profiles.active().name.clone();
+        }
+
+        self.matched = matched;
+
+        let rule = match matched {
+            Some(idx) => &self.rules[idx],
+            None => {
+                return Some(match self.previous.take() {
+                    Some(name) => profiles
+                        .switch(&name)
+                        .map(|_| format!("profile {}, no rule holds", name)),
+                    None => {
+                        profiles.reset();
+                        Ok(String::from("the curves of the controls, no rule holds"))
+                    }
+                })
+            }
+        };
+
+        Some(
+            profiles
+                .switch(&rule.profile)
+                .map(|_| format!("profile {}", rule)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profile::ProfileConfig;
+    use std::{path::PathBuf, process};
+
+    struct Fixture {
+        dir: PathBuf,
+    }
+
+    impl Fixture {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("kool-rules-{}-{}", process::id(), name));
+
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+
+            Self { dir }
+        }
+
+        // batteries have no online file
+        fn supply(&self, name: &str, online: Option<&str>) {
+            let supply = self.dir.join(name);
+
+            fs::create_dir_all(&supply).unwrap();
+
+            if let Some(online) = online {
+                fs::write(supply.join("online"), online).unwrap();
+            }
+        }
+
+        fn process(&self, pid: &str, comm: &str) {
+            let process = self.dir.join(pid);
+
+            fs::create_dir_all(&process).unwrap();
+            fs::write(process.join("comm"), comm).unwrap();
+        }
+
+        fn rule(&self, power: Option<&str>, process: Option<&str>) -> Rule {
+            Rule::from_config(&RuleConfig {
+                profile: String::from("quiet"),
+                power: power.map(String::from),
+                process: process.map(String::from),
+                root: Some(self.dir.to_string_lossy().into_owned()),
+            })
+            .unwrap()
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn is_on_ac_while_any_power_supply_is_online() {
+        let fixture = Fixture::new("power");
+
+        fixture.supply("AC", Some("0\n"));
+        fixture.supply("BAT0", None);
+
+        assert!(fixture.rule(Some("battery"), None).holds());
+        assert!(!fixture.rule(Some("ac"), None).holds());
+
+        fixture.supply("AC", Some("1\n"));
+
+        assert!(!fixture.rule(Some("battery"), None).holds());
+        assert!(fixture.rule(Some("ac"), None).holds());
+    }
+
+    #[test]
+    fn is_on_ac_without_power_supplies() {
+        let fixture = Fixture::new("desktop");
+        let rule = Rule::from_config(&RuleConfig {
+            profile: String::from("quiet"),
+            power: Some(String::from("ac")),
+            process: None,
+            root: Some(fixture.dir.join("missing").to_string_lossy().into_owned()),
+        })
+        .unwrap();
+
+        assert!(rule.holds());
+    }
+
+    #[test]
+    fn matches_the_comm_of_running_processes() {
+        let fixture = Fixture::new("process");
+
+        fixture.process("1", "systemd\n");
+        fixture.process("4242", "firefox\n");
+        // only the numeric entries are processes
+        fixture.process("self", "steam\n");
+        fs::write(fixture.dir.join("uptime"), "1.0 2.0\n").unwrap();
+
+        assert!(fixture.rule(None, Some("firefox")).holds());
+        assert!(!fixture.rule(None, Some("fire")).holds());
+        assert!(!fixture.rule(None, Some("steam")).holds());
+
+        fs::remove_dir_all(fixture.dir.join("4242")).unwrap();
+
+        assert!(!fixture.rule(None, Some("firefox")).holds());
+    }
+
+    #[test]
+    fn needs_every_condition_to_hold() {
+        let fixture = Fixture::new("both");
+
+        // power and process share the root, 4242 is both a supply and a process
+        fixture.supply("4242", Some("0"));
+        fixture.process("4242", "firefox");
+
+        assert!(fixture.rule(Some("battery"), Some("firefox")).holds());
+        assert!(!fixture.rule(Some("ac"), Some("firefox")).holds());
+        assert!(!fixture.rule(Some("battery"), Some("steam")).holds());
+    }
+
+    #[test]
+    fn refuses_rules_without_a_known_condition() {
+        let config = |power: Option<&str>| RuleConfig {
+            profile: String::from("quiet"),
+            power: power.map(String::from),
+            process: None,
+            root: None,
+        };
+
+        for power in [None, Some("mains")] {
+            assert_eq!(
+                Rule::from_config(&config(power)).err().map(|err| err.code()),
+                Some(Code::InvalidRuleConfig)
+            );
+        }
+    }
+
+    #[test]
+    fn switches_the_profile_and_restores_it_once_no_rule_holds() {
+        let fixture = Fixture::new("switch");
+
+        fixture.supply("AC", Some("0"));
+
+        let profiles = Profiles::new(
+            vec![ProfileConfig {
+                name: String::from("quiet"),
+                control: Vec::new(),
+            }],
+            &[],
+            None,
+        )
+        .unwrap();
+
+        let mut rules = Rules::new(vec![fixture.rule(Some("battery"), None)], &profiles).unwrap();
+
+        assert_eq!(
+            rules.apply(&profiles).unwrap().unwrap(),
+            "profile quiet on battery"
+        );
+        assert_eq!(profiles.active().name.as_deref(), Some("quiet"));
+
+        // nothing changed, the profile stays even when switched by hand
+        profiles.reset();
+        assert!(rules.apply(&profiles).is_none());
+
+        fixture.supply("AC", Some("1"));
+
+        assert_eq!(
+            rules.apply(&profiles).unwrap().unwrap(),
+            "the curves of the controls, no rule holds"
+        );
+        assert_eq!(profiles.active().name, None);
+    }
+}
@@ -0,0 +1,410 @@
+use serde::{Deserialize, Serialize};
+use std::{fmt, sync::Arc};
+
+use crate::error::{Code, Error};
+use crate::rules::{Condition, Rule};
+
+// wall clock time, so windows follow daylight saving changes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LocalTime {
+    // 0 is sunday
+    pub weekday: u32,
+    // since midnight
+    pub minutes: u32,
+}
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> LocalTime;
+}
+
+extern "C" {
+    // not exported by the libc crate
+    fn tzset();
+}
+
+pub struct SystemClock {}
+
+impl Clock for SystemClock {
+    fn now(&self) -> LocalTime {
+        unsafe {
+            // picks up changes to the time zone
+            tzset();
+
+            let time = libc::time(std::ptr::null_mut());
+            let mut tm: libc::tm = std::mem::zeroed();
+            libc::localtime_r(&time, &mut tm);
+
+            LocalTime {
+                weekday: tm.tm_wday as u32,
+                minutes: (tm.tm_hour * 60 + tm.tm_min) as u32,
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct ScheduleConfig {
+    // HH:MM, a window ending before it starts runs past midnight
+    pub from: String,
+    pub to: String,
+    // mon, tue... or weekdays and weekend, defaults to every day
+    pub days: Option<Vec<String>>,
+    pub profile: Option<String>,
+    // caps the duty of the controls during the window
+    pub max_percent: Option<f64>,
+    // controls the cap applies to, defaults to all of them
+    pub controls: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Window {
+    from: u32,
+    to: u32,
+    // bit per weekday, 0 is sunday
+    days: u8,
+}
+
+impl Window {
+    const DAYS: [&'static str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+    fn parse_time(time: &str) -> Option<u32> {
+        let (hours, minutes) = time.trim().split_once(':')?;
+        let hours = hours.parse::<u32>().ok()?;
+        let minutes = minutes.parse::<u32>().ok()?;
+
+        if hours > 24 || minutes > 59 || (hours == 24 && minutes > 0) {
+            return None;
+        }
+
+        Some(hours * 60 + minutes)
+    }
+
+    fn parse_days(days: &[String]) -> Option<u8> {
+        let mut mask = 0;
+
+        for day in days {
+            mask |= match day.to_lowercase().as_str() {
+                "weekdays" => 0b0111110,
+                "weekend" => 0b1000001,
+                day => 1 << Window::DAYS.iter().position(|d| day.starts_with(d))?,
+            };
+        }
+
+        Some(mask)
+    }
+
+    pub fn new(config: &ScheduleConfig) -> Result<Self, Error> {
+        let invalid = |what: &str| {
+            Error::new(
+                Code::InvalidScheduleConfig,
+                format!("Invalid {} in schedule {}-{}", what, config.from, config.to),
+            )
+        };
+
+        Ok(Self {
+            from: Window::parse_time(&config.from).ok_or_else(|| invalid("from"))?,
+            to: Window::parse_time(&config.to).ok_or_else(|| invalid("to"))?,
+            days: match &config.days {
+                Some(days) => Window::parse_days(days).ok_or_else(|| invalid("days"))?,
+                None => 0b1111111,
+            },
+        })
+    }
+
+    fn on(&self, weekday: u32) -> bool {
+        self.days & (1 << (weekday % 7)) != 0
+    }
+
+    // the part past midnight belongs to the day the window started
+    pub fn contains(&self, time: LocalTime) -> bool {
+        if self.from <= self.to {
+            self.on(time.weekday) && time.minutes >= self.from && time.minutes < self.to
+        } else if time.minutes >= self.from {
+            self.on(time.weekday)
+        } else {
+            time.minutes < self.to && self.on(time.weekday + 6)
+        }
+    }
+}
+
+impl fmt::Display for Window {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}",
+            self.from / 60,
+            self.from % 60,
+            self.to / 60,
+            self.to % 60
+        )
+    }
+}
+
+pub struct WindowCondition {
+    window: Window,
+    clock: Arc<dyn Clock>,
+}
+
+impl Condition for WindowCondition {
+    fn holds(&self) -> bool {
+        self.window.contains(self.clock.now())
+    }
+}
+
+impl fmt::Display for WindowCondition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "during {}", self.window)
+    }
+}
+
+struct Cap {
+    window: Window,
+    max_percent: f64,
+    controls: Option<Vec<String>>,
+}
+
+// duty limits of the schedules, checked by the controls on every tick
+pub struct Caps {
+    caps: Vec<Cap>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Caps {
+    pub fn max_percent(&self, control: &str) -> Option<f64> {
+        let now = self.clock.now();
+
+        self.caps
+            .iter()
+            .filter(|cap| match &cap.controls {
+                Some(controls) => controls.iter().any(|c| c == control),
+                None => true,
+            })
+            .filter(|cap| cap.window.contains(now))
+            .map(|cap| cap.max_percent)
+            .reduce(f64::min)
+    }
+}
+
+// splits the schedules into rules switching profiles and duty caps
+pub fn create(
+    configs: &[ScheduleConfig],
+    clock: Arc<dyn Clock>,
+) -> Result<(Vec<Rule>, Caps), Error> {
+    let mut rules = Vec::new();
+    let mut caps = Vec::new();
+
+    for config in configs {
+        let window = Window::new(config)?;
+
+        if config.profile.is_none() && config.max_percent.is_none() {
+            return Err(Error::new(
+                Code::InvalidScheduleConfig,
+                format!("Schedule {} needs a profile or max_percent", window),
+            ));
+        }
+
+        if let Some(max_percent) = config.max_percent {
+            caps.push(Cap {
+                window: window.clone(),
+                max_percent,
+                controls: config.controls.clone(),
+            });
+        }
+
+        if let Some(profile) = &config.profile {
+            rules.push(Rule::new(
+                profile.clone(),
+                vec![Box::new(WindowCondition {
+                    window,
+                    clock: Arc::clone(&clock),
+                })],
+            ));
+        }
+    }
+
+    Ok((rules, Caps { caps, clock }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profile::{ProfileConfig, Profiles};
+    use crate::rules::Rules;
+    use std::sync::Mutex;
+
+    const SUN: u32 = 0;
+    const MON: u32 = 1;
+    const TUE: u32 = 2;
+    const SAT: u32 = 6;
+
+    struct FakeClock {
+        now: Mutex<LocalTime>,
+    }
+
+    impl FakeClock {
+        fn set(&self, weekday: u32, time: &str) {
+            *self.now.lock().unwrap() = at(weekday, time);
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> LocalTime {
+            *self.now.lock().unwrap()
+        }
+    }
+
+    fn at(weekday: u32, time: &str) -> LocalTime {
+        LocalTime {
+            weekday,
+            minutes: Window::parse_time(time).unwrap(),
+        }
+    }
+
+    fn config(from: &str, to: &str, days: Option<&[&str]>) -> ScheduleConfig {
+        ScheduleConfig {
+            from: String::from(from),
+            to: String::from(to),
+            days: days.map(|days| days.iter().map(|day| day.to_string()).collect()),
+            profile: Some(String::from("silent")),
+            max_percent: None,
+            controls: None,
+        }
+    }
+
+    fn window(from: &str, to: &str, days: Option<&[&str]>) -> Window {
+        Window::new(&config(from, to, days)).unwrap()
+    }
+
+    fn profiles(default: Option<&str>) -> Profiles {
+        let profiles = ["normal", "silent"]
+            .iter()
+            .map(|name| ProfileConfig {
+                name: name.to_string(),
+                control: Vec::new(),
+            })
+            .collect();
+
+        Profiles::new(profiles, &[], default.map(String::from).as_ref()).unwrap()
+    }
+
+    fn schedule(clock: &Arc<FakeClock>, configs: &[ScheduleConfig], profiles: &Profiles) -> Rules {
+        let clock: Arc<dyn Clock> = Arc::clone(clock) as Arc<dyn Clock>;
+        let (rules, _) = create(configs, clock).unwrap();
+
+        Rules::new(rules, profiles).unwrap()
+    }
+
+    fn clock(weekday: u32, time: &str) -> Arc<FakeClock> {
+        Arc::new(FakeClock {
+            now: Mutex::new(at(weekday, time)),
+        })
+    }
+
+    #[test]
+    fn morning_belongs_to_the_day_the_window_started() {
+        let window = window("18:00", "08:00", Some(&["mon"]));
+
+        assert!(!window.contains(at(MON, "17:59")));
+        assert!(window.contains(at(MON, "18:00")));
+        assert!(window.contains(at(MON, "23:59")));
+        assert!(window.contains(at(TUE, "00:00")));
+        assert!(window.contains(at(TUE, "07:59")));
+        assert!(!window.contains(at(TUE, "08:00")));
+        assert!(!window.contains(at(MON, "07:00")));
+        assert!(!window.contains(at(TUE, "18:00")));
+    }
+
+    #[test]
+    fn wraps_from_saturday_into_sunday() {
+        let window = window("22:00", "06:00", Some(&["sat"]));
+
+        assert!(window.contains(at(SAT, "23:00")));
+        assert!(window.contains(at(SUN, "05:59")));
+        assert!(!window.contains(at(SUN, "23:00")));
+        assert!(!window.contains(at(SAT, "05:00")));
+    }
+
+    #[test]
+    fn masks_weekdays() {
+        let weekdays = window("09:00", "17:00", Some(&["weekdays"]));
+        let weekend = window("09:00", "17:00", Some(&["weekend"]));
+        let days = window("09:00", "17:00", Some(&["Monday", "sat"]));
+        let every = window("09:00", "17:00", None);
+
+        for weekday in 0..7 {
+            let time = at(weekday, "12:00");
+
+            assert_eq!(weekdays.contains(time), (1..=5).contains(&weekday));
+            assert_eq!(weekend.contains(time), weekday == SUN || weekday == SAT);
+            assert_eq!(days.contains(time), weekday == MON || weekday == SAT);
+            assert!(every.contains(time));
+            assert!(!every.contains(at(weekday, "17:00")));
+        }
+
+        assert!(Window::new(&config("09:00", "17:00", Some(&["someday"]))).is_err());
+        assert!(Window::new(&config("24:01", "17:00", None)).is_err());
+        assert!(Window::new(&config("9", "17:00", None)).is_err());
+    }
+
+    #[test]
+    fn reverts_the_profile_at_the_end_of_the_window() {
+        let profiles = profiles(Some("normal"));
+        let clock = clock(MON, "17:59");
+        let mut rules = schedule(&clock, &[config("18:00", "08:00", None)], &profiles);
+
+        assert!(rules.apply(&profiles).is_none());
+        assert_eq!(profiles.active().name.as_deref(), Some("normal"));
+
+        clock.set(MON, "18:00");
+        assert!(rules.apply(&profiles).unwrap().is_ok());
+        assert_eq!(profiles.active().name.as_deref(), Some("silent"));
+
+        clock.set(TUE, "07:59");
+        assert!(rules.apply(&profiles).is_none());
+
+        clock.set(TUE, "08:00");
+        assert!(rules.apply(&profiles).unwrap().is_ok());
+        assert_eq!(profiles.active().name.as_deref(), Some("normal"));
+        assert!(rules.apply(&profiles).is_none());
+    }
+
+    #[test]
+    fn reverts_to_the_curves_of_the_controls_without_a_profile() {
+        let profiles = profiles(None);
+        let clock = clock(MON, "18:30");
+        let mut rules = schedule(&clock, &[config("18:00", "19:00", None)], &profiles);
+
+        rules.apply(&profiles).unwrap().unwrap();
+        assert_eq!(profiles.active().name.as_deref(), Some("silent"));
+
+        clock.set(MON, "19:00");
+        rules.apply(&profiles).unwrap().unwrap();
+        assert_eq!(profiles.active().name, None);
+    }
+
+    #[test]
+    fn follows_the_wall_clock_across_daylight_saving_changes() {
+        let profiles = profiles(Some("normal"));
+        let clock = clock(SAT, "23:00");
+        let mut rules = schedule(&clock, &[config("22:00", "02:30", None)], &profiles);
+
+        rules.apply(&profiles).unwrap().unwrap();
+        assert_eq!(profiles.active().name.as_deref(), Some("silent"));
+
+        // spring forward, 02:00 to 02:59 never happens
+        clock.set(SUN, "01:59");
+        assert!(rules.apply(&profiles).is_none());
+        clock.set(SUN, "03:00");
+        rules.apply(&profiles).unwrap().unwrap();
+        assert_eq!(profiles.active().name.as_deref(), Some("normal"));
+
+        // fall back, 01:00 to 01:59 happens twice and stays in the window
+        clock.set(SUN, "22:00");
+        rules.apply(&profiles).unwrap().unwrap();
+        clock.set(MON, "01:59");
+        assert!(rules.apply(&profiles).is_none());
+        clock.set(MON, "01:00");
+        assert!(rules.apply(&profiles).is_none());
+        assert_eq!(profiles.active().name.as_deref(), Some("silent"));
+    }
+}
@@ -1,4 +1,4 @@
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 use crate::event;
 
@@ -6,14 +6,15 @@ type EventType = Arc<event::Event>;
 type ListenerFunc = dyn Fn(EventType) + 'static + Send;
 type Listener = Box<ListenerFunc>;
 
+#[derive(Default)]
 pub struct Signal {
-    listeners: Vec<Listener>
+    listeners: Vec<Listener>,
 }
 
 impl Signal {
     pub fn new() -> Self {
         Self {
-            listeners: Vec::new()
+            listeners: Vec::new(),
         }
     }
 
@@ -25,8 +26,45 @@ impl Signal {
         }
     }
 
-    pub fn connect<F>(&mut self, f: F) where F: Fn(EventType) + 'static + Send {
+    pub fn connect<F>(&mut self, f: F)
+    where
+        F: Fn(EventType) + 'static + Send,
+    {
         self.listeners.push(Box::new(f));
     }
+
+    // only events matching the filter reach the listener
+    pub fn connect_filtered<P, F>(&mut self, filter: P, f: F)
+    where
+        P: Fn(&event::Event) -> bool + 'static + Send,
+        F: Fn(EventType) + 'static + Send,
+    {
+        self.listeners.push(Box::new(move |e| {
+            if filter(e.as_ref()) {
+                f(e);
+            }
+        }));
+    }
 }
 
+// handed to jobs so their events are marked with where they came from
+#[derive(Clone)]
+pub struct Emitter {
+    origin: Option<String>,
+    signal: Arc<Mutex<Signal>>,
+}
+
+impl Emitter {
+    pub fn new(origin: Option<String>, signal: Arc<Mutex<Signal>>) -> Self {
+        Self { origin, signal }
+    }
+
+    pub fn emit(&self, e: event::Event) {
+        let e = match (&self.origin, &e.origin) {
+            (Some(origin), None) => e.with_origin(origin),
+            _ => e,
+        };
+
+        self.signal.lock().unwrap().notify(e);
+    }
+}
@@ -0,0 +1,502 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs,
+    io::{BufRead, BufReader, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use crate::async_pool::Submitter;
+use crate::control::{Config, Control, Status};
+use crate::error::{Code, Error};
+use crate::profile::Profiles;
+
+// one json object per line in both directions
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "command", rename_all = "lowercase")]
+pub enum Request {
+    Status,
+    // duration in seconds, held until released when missing
+    Override {
+        control: String,
+        percent: f64,
+        duration: Option<u64>,
+    },
+    Release {
+        control: String,
+    },
+    Reload,
+    Profile {
+        name: String,
+    },
+    // keeps sending the status every interval milliseconds until the client goes away,
+    // defaults to 1000
+    Watch {
+        interval: Option<u64>,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct Response {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub controls: Option<Vec<Status>>,
+}
+
+impl Response {
+    fn message(message: String) -> Self {
+        Self {
+            ok: true,
+            message: Some(message),
+            ..Default::default()
+        }
+    }
+
+    fn error(error: String) -> Self {
+        Self {
+            ok: false,
+            error: Some(error),
+            ..Default::default()
+        }
+    }
+}
+
+// reads the control configs again for reload
+type Loader = dyn Fn() -> Result<Vec<Config>, Error> + Send + Sync;
+
+pub struct Server {
+    controls: HashMap<String, Arc<Mutex<Control>>>,
+    profiles: Arc<Profiles>,
+    loader: Box<Loader>,
+}
+
+impl Server {
+    pub fn new<F>(
+        controls: HashMap<String, Arc<Mutex<Control>>>,
+        profiles: Arc<Profiles>,
+        loader: F,
+    ) -> Self
+    where
+        F: Fn() -> Result<Vec<Config>, Error> + 'static + Send + Sync,
+    {
+        Self {
+            controls,
+            profiles,
+            loader: Box::new(loader),
+        }
+    }
+
+    fn control(&self, name: &str) -> Result<&Arc<Mutex<Control>>, Response> {
+        self.controls
+            .get(name)
+            .ok_or_else(|| Response::error(format!("Unknown control: {}", name)))
+    }
+
+    pub fn handle(&self, request: &Request) -> Response {
+        let result = match request {
+            Request::Status | Request::Watch { .. } => Ok(self.status()),
+            Request::Override {
+                control,
+                percent,
+                duration,
+            } => self.control(control).map(|c| {
+                c.lock()
+                    .unwrap()
+                    .set_manual(*percent, duration.map(Duration::from_secs));
+
+                Response::message(format!("Control {} held at {}%", control, percent))
+            }),
+            Request::Release { control } => self.control(control).map(|c| {
+                if c.lock().unwrap().release() {
+                    Response::message(format!("Control {} released", control))
+                } else {
+                    Response::message(format!("Control {} was not overridden", control))
+                }
+            }),
+            Request::Reload => Ok(self.reload()),
+            Request::Profile { name } => match self.profiles.switch(name) {
+                Ok(()) => Ok(Response::message(format!("Switched to profile {}", name))),
+                Err(err) => Err(Response::error(err.message().clone())),
+            },
+        };
+
+        result.unwrap_or_else(|response| response)
+    }
+
+    fn status(&self) -> Response {
+        let mut controls = self
+            .controls
+            .values()
+            .map(|control| control.lock().unwrap().status())
+            .collect::<Vec<_>>();
+
+        controls.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Response {
+            ok: true,
+            profile: self.profiles.active().name.clone(),
+            controls: Some(controls),
+            ..Default::default()
+        }
+    }
+
+    fn reload(&self) -> Response {
+        let configs = match (self.loader)() {
+            Ok(configs) => configs,
+            Err(err) => return Response::error(err.message().clone()),
+        };
+
+        let mut reloaded = 0;
+        let mut errors = Vec::new();
+
+        for config in configs.iter() {
+            match self.controls.get(&config.name) {
+                Some(control) => match control.lock().unwrap().reload(config.clone()) {
+                    Ok(()) => reloaded += 1,
+                    Err(err) => errors.push(err.message().clone()),
+                },
+                None => errors.push(format!(
+                    "New control {} needs a restart to start",
+                    config.name
+                )),
+            }
+        }
+
+        for name in self.controls.keys() {
+            if !configs.iter().any(|config| &config.name == name) {
+                errors.push(format!("Removed control {} needs a restart to stop", name));
+            }
+        }
+
+        if errors.is_empty() {
+            Response::message(format!("Reloaded {} controls", reloaded))
+        } else {
+            Response::error(format!(
+                "Reloaded {} controls, {}",
+                reloaded,
+                errors.join(", ")
+            ))
+        }
+    }
+
+    // refuses to take over the socket of a running instance, a stale one is removed
+    pub fn listen(server: Arc<Server>, path: &str, submitter: Submitter) -> Result<(), Error> {
+        if UnixStream::connect(path).is_ok() {
+            return Err(Error::new(
+                Code::InvalidSocketConfig,
+                format!("Socket {} is in use, is kool already running?", path),
+            ));
+        }
+
+        let _ = fs::remove_file(path);
+
+        let listener = UnixListener::bind(path).map_err(|err| {
+            Error::new(
+                Code::InvalidSocketConfig,
+                format!("Cannot listen on {}: {}", path, err),
+            )
+        })?;
+
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let server = Arc::clone(&server);
+                let submitter = submitter.clone();
+
+                thread::spawn(move || Server::serve(server, stream, submitter));
+            }
+        });
+
+        Ok(())
+    }
+
+    // the connection is read here, the requests themselves run on the pool
+    fn serve(server: Arc<Server>, mut stream: UnixStream, submitter: Submitter) {
+        let reader = match stream.try_clone() {
+            Ok(stream) => BufReader::new(stream),
+            Err(_) => return,
+        };
+
+        for line in reader.lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => return,
+            };
+
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let response = match serde_json::from_str::<Request>(&line) {
+                Ok(Request::Watch { interval }) => {
+                    let interval = Duration::from_millis(interval.unwrap_or(1000).max(100));
+                    return Server::watch(server, stream, submitter, interval);
+                }
+                Ok(request) => Server::submit(&server, &submitter, request),
+                Err(err) => Response::error(format!("Invalid request: {}", err)),
+            };
+
+            if Server::send(&mut stream, &response).is_err() {
+                return;
+            }
+        }
+    }
+
+    fn submit(server: &Arc<Server>, submitter: &Submitter, request: Request) -> Response {
+        let (tx, rx) = mpsc::channel();
+        let server = Arc::clone(server);
+
+        submitter.submit(move || {
+            let _ = tx.send(server.handle(&request));
+            None
+        });
+
+        rx.recv()
+            .unwrap_or_else(|_| Response::error(String::from("Request was dropped")))
+    }
+
+    fn send(stream: &mut UnixStream, response: &Response) -> std::io::Result<()> {
+        let line = serde_json::to_string(response).unwrap_or_default();
+        writeln!(stream, "{}", line)
+    }
+
+    // takes over the connection, stops once the client is gone
+    fn watch(
+        server: Arc<Server>,
+        mut stream: UnixStream,
+        submitter: Submitter,
+        interval: Duration,
+    ) {
+        loop {
+            let response = Server::submit(&server, &submitter, Request::Status);
+
+            if Server::send(&mut stream, &response).is_err() {
+                return;
+            }
+
+            thread::sleep(interval);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::async_pool::AsyncPool;
+    use crate::builder::ControlBuilder;
+    use crate::control::Context;
+    use crate::profile::ProfileConfig;
+    use crate::source::Source;
+    use std::{path::PathBuf, process};
+
+    struct FixedSource {}
+
+    impl Source for FixedSource {
+        fn get(&mut self, _timeout: Duration) -> Result<i64, Error> {
+            Ok(45000)
+        }
+    }
+
+    struct Fixture {
+        dir: PathBuf,
+        socket: String,
+        pool: AsyncPool,
+    }
+
+    impl Fixture {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("kool-socket-{}-{}", process::id(), name));
+
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+
+            let dest = dir.join("pwm1");
+            fs::write(&dest, "0").unwrap();
+
+            let control = ControlBuilder::new("fan")
+                .custom_source(Box::new(FixedSource {}))
+                .dest(&dest.to_string_lossy(), 0, 255)
+                .points(&[(30000, 20), (70000, 100)])
+                .build(&Context::default())
+                .unwrap();
+
+            let profiles = vec![ProfileConfig {
+                name: String::from("silent"),
+                control: Vec::new(),
+            }];
+
+            let server = Server::new(
+                HashMap::from([(String::from("fan"), Arc::new(Mutex::new(control)))]),
+                Arc::new(Profiles::new(profiles, &[], None).unwrap()),
+                || Ok(Vec::new()),
+            );
+
+            let pool = AsyncPool::new(2, Duration::from_millis(10));
+            let socket = dir.join("kool.sock").to_string_lossy().to_string();
+
+            Server::listen(Arc::new(server), &socket, pool.submitter()).unwrap();
+
+            Self { dir, socket, pool }
+        }
+
+        fn connect(&self) -> Connection {
+            let stream = UnixStream::connect(&self.socket).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+
+            Connection {
+                reader: BufReader::new(stream.try_clone().unwrap()),
+                stream,
+            }
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            self.pool.shutdown();
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    struct Connection {
+        stream: UnixStream,
+        reader: BufReader<UnixStream>,
+    }
+
+    impl Connection {
+        fn send(&mut self, line: &str) {
+            writeln!(self.stream, "{}", line).unwrap();
+        }
+
+        fn receive(&mut self) -> Response {
+            let mut line = String::new();
+            self.reader.read_line(&mut line).unwrap();
+            serde_json::from_str(&line).unwrap()
+        }
+
+        fn request(&mut self, line: &str) -> Response {
+            self.send(line);
+            self.receive()
+        }
+    }
+
+    #[test]
+    fn answers_requests_on_the_same_connection() {
+        let fixture = Fixture::new("requests");
+        let mut connection = fixture.connect();
+
+        let status = connection.request(r#"{"command":"status"}"#);
+        assert!(status.ok);
+        assert_eq!(status.profile, None);
+        assert_eq!(status.controls.unwrap()[0].name, "fan");
+
+        let set = connection
+            .request(r#"{"command":"override","control":"fan","percent":80,"duration":600}"#);
+        assert_eq!(set.message.as_deref(), Some("Control fan held at 80%"));
+
+        let status = connection.request(r#"{"command":"status"}"#);
+        let controls = status.controls.unwrap();
+        assert_eq!(controls[0].manual, Some(80.0));
+        assert!(controls[0].manual_remaining.unwrap() > 590);
+
+        let release = connection.request(r#"{"command":"release","control":"fan"}"#);
+        assert_eq!(release.message.as_deref(), Some("Control fan released"));
+
+        let release = connection.request(r#"{"command":"release","control":"fan"}"#);
+        assert_eq!(
+            release.message.as_deref(),
+            Some("Control fan was not overridden")
+        );
+
+        let profile = connection.request(r#"{"command":"profile","name":"silent"}"#);
+        assert!(profile.ok);
+        let status = connection.request(r#"{"command":"status"}"#);
+        assert_eq!(status.profile.as_deref(), Some("silent"));
+
+        let reload = connection.request(r#"{"command":"reload"}"#);
+        assert_eq!(
+            reload.error.as_deref(),
+            Some("Reloaded 0 controls, Removed control fan needs a restart to stop")
+        );
+    }
+
+    #[test]
+    fn reports_malformed_lines_and_keeps_the_connection() {
+        let fixture = Fixture::new("malformed");
+        let mut connection = fixture.connect();
+
+        let response = connection.request("{\"command\":");
+        assert!(!response.ok);
+        assert!(response.error.unwrap().starts_with("Invalid request: "));
+
+        // blank lines are skipped without an answer
+        connection.send("");
+
+        let response = connection.request(r#"{"command":"override","control":"fan"}"#);
+        assert!(response.error.unwrap().contains("missing field `percent`"));
+
+        let response = connection.request(r#"{"command":"status"}"#);
+        assert!(response.ok);
+    }
+
+    #[test]
+    fn reports_unknown_commands_controls_and_profiles() {
+        let fixture = Fixture::new("unknown");
+        let mut connection = fixture.connect();
+
+        let response = connection.request(r#"{"command":"explode"}"#);
+        assert!(!response.ok);
+        assert!(response
+            .error
+            .unwrap()
+            .contains("unknown variant `explode`"));
+
+        let response = connection.request(r#"{"command":"release","control":"pump"}"#);
+        assert_eq!(response.error.as_deref(), Some("Unknown control: pump"));
+
+        let response = connection.request(r#"{"command":"profile","name":"loud"}"#);
+        assert_eq!(response.error.as_deref(), Some("Unknown profile: loud"));
+    }
+
+    #[test]
+    fn keeps_serving_after_a_client_disconnects() {
+        let fixture = Fixture::new("disconnect");
+
+        // gone before reading the answer
+        let mut connection = fixture.connect();
+        connection.send(r#"{"command":"status"}"#);
+        drop(connection);
+
+        // gone while watching
+        let mut connection = fixture.connect();
+        connection.send(r#"{"command":"watch","interval":100}"#);
+        assert!(connection.receive().ok);
+        assert!(connection.receive().ok);
+        drop(connection);
+
+        let mut connection = fixture.connect();
+        assert!(connection.request(r#"{"command":"status"}"#).ok);
+
+        // a second instance doesn't take over the socket
+        let err = Server::listen(
+            Arc::new(Server::new(
+                HashMap::new(),
+                Arc::new(Profiles::new(Vec::new(), &[], None).unwrap()),
+                || Ok(Vec::new()),
+            )),
+            &fixture.socket,
+            fixture.pool.submitter(),
+        )
+        .unwrap_err();
+        assert!(err.message().contains("is in use"));
+    }
+}
@@ -1,15 +1,190 @@
+use serde::{Deserialize, Serialize};
 use std::{
+    collections::HashMap,
     fs::{self, OpenOptions},
-    io::Read,
+    io::{Read, Seek, SeekFrom},
     process::{Command, Stdio},
+    sync::{Arc, Mutex},
     thread::sleep,
-    time::Duration,
+    time::{Duration, Instant, SystemTime},
 };
 
 use crate::error::{self, Error};
+use crate::expr::ExprSource;
 
-pub trait Source {
+/// Something a control reads, usually a temperature.
+pub trait Source: Send {
+    /// Takes a reading, giving up after `timeout`.
     fn get(&mut self, timeout: Duration) -> Result<i64, Error>;
+
+    /// Describes the last reading when it isn't a plain value.
+    fn label(&self) -> Option<String> {
+        None
+    }
+
+    /// The last reading before the checks, when it differs from the value returned by `get`.
+    fn raw(&self) -> Option<i64> {
+        None
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct SourceConfig {
+    pub path: String,
+    #[serde(rename = "type")]
+    pub src_type: String,
+    pub args: Option<Vec<String>>,
+    // value = raw * scale + offset, applied before aggregating
+    pub scale: Option<f64>,
+    pub offset: Option<i64>,
+    // only used by the weighted aggregate
+    pub weight: Option<f64>,
+    #[serde(flatten)]
+    pub checks: Checks,
+}
+
+// plausibility checks wrapped around a source
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Default)]
+pub struct Checks {
+    // the reading is stale after this many polls in a row with the same value
+    pub max_unchanged_polls: Option<u32>,
+    // in milliseconds, the reading is stale when it hasn't changed for this long
+    pub stale_after: Option<u64>,
+    // compare the modification time of a file source instead of its value,
+    // for files written by other programs, sysfs doesn't update it
+    pub stale_mtime: Option<bool>,
+    // readings outside of these are errors, in the units of the source before scale and offset
+    pub valid_min: Option<i64>,
+    pub valid_max: Option<i64>,
+    // faster changes are held until the next reading confirms them
+    pub max_delta_per_sec: Option<f64>,
+}
+
+impl Checks {
+    pub fn apply(
+        &self,
+        source: Box<dyn Source>,
+        src_type: &str,
+        path: &str,
+    ) -> Result<Box<dyn Source>, Error> {
+        let mut source = source;
+
+        if self.max_unchanged_polls.is_some() || self.stale_after.is_some() {
+            let mtime_path = match self.stale_mtime {
+                Some(true) if src_type == "file" => Some(path.to_string()),
+                Some(true) => {
+                    return Err(Error::new(
+                        error::Code::InvalidSourceConfig,
+                        format!(
+                            "Option stale_mtime only works for file sources, not {}",
+                            path
+                        ),
+                    ))
+                }
+                _ => None,
+            };
+
+            source = Box::new(StaleSource::new(
+                source,
+                path,
+                self.max_unchanged_polls,
+                self.stale_after.map(Duration::from_millis),
+                mtime_path,
+            ));
+        }
+
+        if let (Some(min), Some(max)) = (self.valid_min, self.valid_max) {
+            if min > max {
+                return Err(Error::new(
+                    error::Code::InvalidSourceConfig,
+                    format!("valid_min {} is above valid_max {} for {}", min, max, path),
+                ));
+            }
+        }
+
+        if self.max_delta_per_sec.is_some_and(|delta| delta <= 0.0) {
+            return Err(Error::new(
+                error::Code::InvalidSourceConfig,
+                format!("max_delta_per_sec has to be positive for {}", path),
+            ));
+        }
+
+        if self.valid_min.is_some() || self.valid_max.is_some() || self.max_delta_per_sec.is_some()
+        {
+            source = Box::new(PlausibleSource::new(
+                source,
+                path,
+                self.valid_min,
+                self.valid_max,
+                self.max_delta_per_sec,
+            ));
+        }
+
+        Ok(source)
+    }
+}
+
+// shared sources by name
+pub type Registry = HashMap<String, Arc<SharedSource>>;
+
+impl SourceConfig {
+    pub fn create(&self, shared: &Registry) -> Result<Box<dyn Source>, Error> {
+        let source = create(&self.src_type, &self.path, self.args.as_ref(), shared)?;
+        let source = self.checks.apply(source, &self.src_type, &self.path)?;
+
+        Ok(Box::new(ScaledSource::new(
+            source,
+            self.scale.unwrap_or(1.0),
+            self.offset.unwrap_or(0),
+        )))
+    }
+}
+
+pub fn create(
+    src_type: &str,
+    path: &String,
+    args: Option<&Vec<String>>,
+    shared: &Registry,
+) -> Result<Box<dyn Source>, Error> {
+    match src_type.to_lowercase().as_str() {
+        "file" => {
+            if let Some(src) = FileSource::new(path) {
+                Ok(Box::new(src))
+            } else {
+                Err(Error::new(
+                    error::Code::General,
+                    format!("Cannot open/find file: {}", path),
+                ))
+            }
+        }
+        "program" => Ok(Box::new(ProgramSource::new(path, args))),
+        // path is /proc/stat, args can pick a single core like cpu3
+        "cpu_usage" => Ok(Box::new(CpuUsageSource::new(
+            path,
+            args.and_then(|args| args.first()),
+        )?)),
+        // path is /proc/loadavg, args can pick the 1, 5 or 15 minute average
+        "loadavg" => Ok(Box::new(LoadAvgSource::new(
+            path,
+            args.and_then(|args| args.first()),
+        )?)),
+        // the path is an expression over shared sources
+        "expr" => Ok(Box::new(ExprSource::new(path, shared)?)),
+        // path is /sys/devices/system/cpu
+        "cpu_freq" => Ok(Box::new(CpuFreqSource::new(path)?)),
+        // the path is the name of the shared source
+        "shared" => match shared.get(path) {
+            Some(src) => Ok(Box::new(CachedSource::new(Arc::clone(src)))),
+            None => Err(Error::new(
+                error::Code::InvalidSourceConfig,
+                format!("Unknown shared source: {}", path),
+            )),
+        },
+        _ => Err(Error::new(
+            error::Code::InvalidSourceConfig,
+            format!("Unknown source type: {}", src_type),
+        )),
+    }
 }
 
 pub struct ProgramSource {
@@ -41,11 +216,11 @@ impl Source for ProgramSource {
                         if let Some(mut stdout) = child.stdout {
                             let mut result = String::new();
 
-                            stdout.read_to_string(&mut result);
+                            let _ = stdout.read_to_string(&mut result);
 
-                            match result.parse::<i64>() {
+                            match result.trim().parse::<i64>() {
                                 Ok(n) => Ok(n),
-                                Err(err) => Err(Error::new(
+                                Err(_) => Err(Error::new(
                                     error::Code::UnableToParse,
                                     String::from("Unable to parse response from command"),
                                 )),
@@ -63,7 +238,7 @@ impl Source for ProgramSource {
                         ))
                     }
                 } else {
-                    child.kill();
+                    let _ = child.kill();
                     Err(Error::new(
                         error::Code::Timeout,
                         String::from("Command timedout"),
@@ -97,9 +272,11 @@ impl Source for FileSource {
     fn get(&mut self, _timeout: Duration) -> Result<i64, Error> {
         let mut buffer = String::new();
 
-        self.file.read_to_string(&mut buffer);
+        // sysfs files need to be read from the start every time
+        let _ = self.file.seek(SeekFrom::Start(0));
+        let _ = self.file.read_to_string(&mut buffer);
 
-        match buffer.parse::<i64>() {
+        match buffer.trim().parse::<i64>() {
             Ok(n) => Ok(n),
             Err(_) => Err(Error::new(
                 error::Code::UnableToParse,
@@ -108,3 +285,1099 @@ impl Source for FileSource {
         }
     }
 }
+
+fn read_proc(path: &str) -> Result<String, Error> {
+    fs::read_to_string(path).map_err(|err| {
+        Error::new(
+            error::Code::General,
+            format!("Cannot read {}: {}", path, err),
+        )
+    })
+}
+
+fn unparsable(path: &str) -> Error {
+    Error::new(
+        error::Code::UnableToParse,
+        format!("Unable to parse {}", path),
+    )
+}
+
+// busy time of the cpu since the previous reading in percent
+pub struct CpuUsageSource {
+    path: String,
+    line: String,
+    // idle and total jiffies of the previous reading
+    last: (u64, u64),
+    last_value: i64,
+}
+
+impl CpuUsageSource {
+    pub fn new(path: &str, line: Option<&String>) -> Result<Self, Error> {
+        let mut source = Self {
+            path: path.to_string(),
+            line: line.cloned().unwrap_or(String::from("cpu")),
+            last: (0, 0),
+            last_value: 0,
+        };
+
+        // the first reading would otherwise be the average since boot
+        source.last = source.jiffies()?;
+
+        Ok(source)
+    }
+
+    fn jiffies(&self) -> Result<(u64, u64), Error> {
+        let stat = read_proc(&self.path)?;
+
+        let fields = stat
+            .lines()
+            .map(|line| line.split_whitespace())
+            .find_map(|mut fields| {
+                (fields.next() == Some(self.line.as_str())).then(|| {
+                    fields
+                        .map(|field| field.parse::<u64>())
+                        .collect::<Result<Vec<_>, _>>()
+                })
+            })
+            .and_then(|fields| fields.ok())
+            .filter(|fields| fields.len() >= 4)
+            .ok_or_else(|| unparsable(&self.path))?;
+
+        // guest time is already part of user time
+        let total = fields.iter().take(8).sum();
+        // idle and iowait
+        let idle = fields[3] + fields.get(4).unwrap_or(&0);
+
+        Ok((idle, total))
+    }
+}
+
+impl Source for CpuUsageSource {
+    fn get(&mut self, _timeout: Duration) -> Result<i64, Error> {
+        let (idle, total) = self.jiffies()?;
+        let (last_idle, last_total) = self.last;
+
+        let total_delta = total.saturating_sub(last_total);
+
+        // read twice within a jiffy
+        if total_delta == 0 {
+            return Ok(self.last_value);
+        }
+
+        let busy_delta = total_delta.saturating_sub(idle.saturating_sub(last_idle));
+
+        self.last = (idle, total);
+        self.last_value = (busy_delta * 100 / total_delta) as i64;
+
+        Ok(self.last_value)
+    }
+}
+
+// load average times 100, so 1.5 reads as 150
+pub struct LoadAvgSource {
+    path: String,
+    field: usize,
+}
+
+impl LoadAvgSource {
+    pub fn new(path: &str, minutes: Option<&String>) -> Result<Self, Error> {
+        let field = match minutes.map(|m| m.as_str()).unwrap_or("1") {
+            "1" => 0,
+            "5" => 1,
+            "15" => 2,
+            minutes => {
+                return Err(Error::new(
+                    error::Code::InvalidSourceConfig,
+                    format!("Load average over {} minutes doesn't exist", minutes),
+                ))
+            }
+        };
+
+        read_proc(path)?;
+
+        Ok(Self {
+            path: path.to_string(),
+            field,
+        })
+    }
+}
+
+impl Source for LoadAvgSource {
+    fn get(&mut self, _timeout: Duration) -> Result<i64, Error> {
+        read_proc(&self.path)?
+            .split_whitespace()
+            .nth(self.field)
+            .and_then(|load| load.parse::<f64>().ok())
+            .map(|load| (load * 100.0).round() as i64)
+            .ok_or_else(|| unparsable(&self.path))
+    }
+}
+
+// highest current frequency of all the cores in MHz
+pub struct CpuFreqSource {
+    cores: Vec<FileSource>,
+}
+
+impl CpuFreqSource {
+    pub fn new(path: &String) -> Result<Self, Error> {
+        let mut cores = Vec::new();
+
+        if let Ok(entries) = fs::read_dir(path) {
+            for entry in entries.flatten() {
+                let name = entry.file_name().to_string_lossy().to_string();
+
+                if !name.starts_with("cpu") || name[3..].parse::<u32>().is_err() {
+                    continue;
+                }
+
+                let freq = entry.path().join("cpufreq/scaling_cur_freq");
+
+                if let Some(core) = FileSource::new(&freq.to_string_lossy().to_string()) {
+                    cores.push(core);
+                }
+            }
+        }
+
+        if cores.is_empty() {
+            return Err(Error::new(
+                error::Code::InvalidSourceConfig,
+                format!("No cpufreq cores found in {}", path),
+            ));
+        }
+
+        Ok(Self { cores })
+    }
+}
+
+impl Source for CpuFreqSource {
+    fn get(&mut self, timeout: Duration) -> Result<i64, Error> {
+        let mut max = None;
+
+        for core in self.cores.iter_mut() {
+            // offline cores can't be read, skip them
+            if let Ok(khz) = core.get(timeout) {
+                max = max.max(Some(khz / 1000));
+            }
+        }
+
+        max.ok_or_else(|| {
+            Error::new(
+                error::Code::UnableToParse,
+                String::from("Unable to read the frequency of any core"),
+            )
+        })
+    }
+}
+
+pub struct ScaledSource {
+    source: Box<dyn Source>,
+    scale: f64,
+    offset: i64,
+}
+
+impl ScaledSource {
+    pub fn new(source: Box<dyn Source>, scale: f64, offset: i64) -> Self {
+        Self {
+            source,
+            scale,
+            offset,
+        }
+    }
+}
+
+impl Source for ScaledSource {
+    fn get(&mut self, timeout: Duration) -> Result<i64, Error> {
+        let value = self.source.get(timeout)?;
+
+        Ok((value as f64 * self.scale) as i64 + self.offset)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Aggregate {
+    Max,
+    Min,
+    Avg,
+    Weighted,
+}
+
+impl Aggregate {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "max" => Some(Aggregate::Max),
+            "min" => Some(Aggregate::Min),
+            "avg" => Some(Aggregate::Avg),
+            "weighted" => Some(Aggregate::Weighted),
+            _ => None,
+        }
+    }
+}
+
+// what to do when one of the aggregated sources fails
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FailurePolicy {
+    Skip,
+    Fail,
+}
+
+impl FailurePolicy {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "skip" => Some(FailurePolicy::Skip),
+            "fail" => Some(FailurePolicy::Fail),
+            _ => None,
+        }
+    }
+}
+
+pub struct AggregateSource {
+    sources: Vec<(Box<dyn Source>, f64)>,
+    aggregate: Aggregate,
+    policy: FailurePolicy,
+}
+
+impl AggregateSource {
+    pub fn new(aggregate: Aggregate, policy: FailurePolicy) -> Self {
+        Self {
+            sources: Vec::new(),
+            aggregate,
+            policy,
+        }
+    }
+
+    pub fn from_configs(
+        configs: &[SourceConfig],
+        aggregate: Aggregate,
+        policy: FailurePolicy,
+        shared: &Registry,
+    ) -> Result<Self, Error> {
+        let mut aggregate_source = AggregateSource::new(aggregate, policy);
+
+        for config in configs {
+            aggregate_source.push(config.create(shared)?, config.weight.unwrap_or(1.0));
+        }
+
+        if aggregate_source.sources.is_empty() {
+            return Err(Error::new(
+                error::Code::InvalidSourceConfig,
+                String::from("Aggregate source needs at least one source"),
+            ));
+        }
+
+        Ok(aggregate_source)
+    }
+
+    pub fn push(&mut self, source: Box<dyn Source>, weight: f64) {
+        self.sources.push((source, weight));
+    }
+}
+
+impl Source for AggregateSource {
+    fn get(&mut self, timeout: Duration) -> Result<i64, Error> {
+        // the sources are read one after another, share the time between them
+        let timeout = timeout / self.sources.len().max(1) as u32;
+
+        let mut values = Vec::with_capacity(self.sources.len());
+        let mut last_err = None;
+
+        for (source, weight) in self.sources.iter_mut() {
+            match source.get(timeout) {
+                Ok(value) => values.push((value, *weight)),
+                Err(err) => {
+                    if self.policy == FailurePolicy::Fail {
+                        return Err(err);
+                    }
+                    last_err = Some(err);
+                }
+            }
+        }
+
+        if values.is_empty() {
+            return Err(last_err.unwrap_or_else(|| {
+                Error::new(
+                    error::Code::General,
+                    String::from("Aggregate source has no sources"),
+                )
+            }));
+        }
+
+        let value = match self.aggregate {
+            Aggregate::Max => values.iter().map(|(v, _)| *v).max().unwrap(),
+            Aggregate::Min => values.iter().map(|(v, _)| *v).min().unwrap(),
+            Aggregate::Avg => values.iter().map(|(v, _)| *v).sum::<i64>() / values.len() as i64,
+            Aggregate::Weighted => {
+                let total: f64 = values.iter().map(|(_, w)| *w).sum();
+
+                if total <= 0.0 {
+                    return Err(Error::new(
+                        error::Code::InvalidSourceConfig,
+                        String::from("Weights of the aggregated sources add up to zero"),
+                    ));
+                }
+
+                (values.iter().map(|(v, w)| *v as f64 * w).sum::<f64>() / total) as i64
+            }
+        };
+
+        Ok(value)
+    }
+}
+
+// some drivers keep returning the last value once the sensor died
+pub struct StaleSource {
+    source: Box<dyn Source>,
+    name: String,
+    max_unchanged_polls: Option<u32>,
+    stale_after: Option<Duration>,
+    // compared instead of the value when set
+    mtime_path: Option<String>,
+    last: Option<i64>,
+    last_mtime: Option<SystemTime>,
+    unchanged_polls: u32,
+    changed_at: Instant,
+    clock: Box<dyn Fn() -> Instant + Send>,
+}
+
+impl StaleSource {
+    pub fn new(
+        source: Box<dyn Source>,
+        name: &str,
+        max_unchanged_polls: Option<u32>,
+        stale_after: Option<Duration>,
+        mtime_path: Option<String>,
+    ) -> Self {
+        StaleSource::with_clock(
+            source,
+            name,
+            max_unchanged_polls,
+            stale_after,
+            mtime_path,
+            Box::new(Instant::now),
+        )
+    }
+
+    pub fn with_clock(
+        source: Box<dyn Source>,
+        name: &str,
+        max_unchanged_polls: Option<u32>,
+        stale_after: Option<Duration>,
+        mtime_path: Option<String>,
+        clock: Box<dyn Fn() -> Instant + Send>,
+    ) -> Self {
+        Self {
+            source,
+            name: name.to_string(),
+            max_unchanged_polls,
+            stale_after,
+            mtime_path,
+            last: None,
+            last_mtime: None,
+            unchanged_polls: 0,
+            changed_at: clock(),
+            clock,
+        }
+    }
+
+    fn changed(&mut self, value: i64) -> bool {
+        match &self.mtime_path {
+            Some(path) => {
+                let mtime = fs::metadata(path).and_then(|m| m.modified()).ok();
+                let changed = mtime != self.last_mtime;
+                self.last_mtime = mtime;
+                changed
+            }
+            None => {
+                let changed = Some(value) != self.last;
+                self.last = Some(value);
+                changed
+            }
+        }
+    }
+}
+
+impl Source for StaleSource {
+    fn get(&mut self, timeout: Duration) -> Result<i64, Error> {
+        let value = self.source.get(timeout)?;
+        let now = (self.clock)();
+
+        if self.changed(value) {
+            self.unchanged_polls = 0;
+            self.changed_at = now;
+        } else {
+            self.unchanged_polls += 1;
+        }
+
+        let polls = self
+            .max_unchanged_polls
+            .is_some_and(|max| self.unchanged_polls >= max);
+        let unchanged = now.saturating_duration_since(self.changed_at);
+        let time = self.stale_after.is_some_and(|after| unchanged >= after);
+
+        if polls || time {
+            return Err(Error::new(
+                error::Code::StaleReading,
+                format!(
+                    "Reading of {} stuck at {} for {} polls ({}s)",
+                    self.name,
+                    value,
+                    self.unchanged_polls,
+                    unchanged.as_secs()
+                ),
+            ));
+        }
+
+        Ok(value)
+    }
+
+    fn label(&self) -> Option<String> {
+        self.source.label()
+    }
+
+    fn raw(&self) -> Option<i64> {
+        self.source.raw()
+    }
+}
+
+// a flaky sensor reading -127 once shouldn't stop or max out the fans
+pub struct PlausibleSource {
+    source: Box<dyn Source>,
+    name: String,
+    valid_min: Option<i64>,
+    valid_max: Option<i64>,
+    max_delta_per_sec: Option<f64>,
+    // last accepted reading
+    last: Option<(i64, Instant)>,
+    // a jump waiting for confirmation
+    pending: Option<(i64, Instant)>,
+    // direction of a confirmed jump, readings carrying on with it aren't held
+    trend: i64,
+}
+
+impl PlausibleSource {
+    pub fn new(
+        source: Box<dyn Source>,
+        name: &str,
+        valid_min: Option<i64>,
+        valid_max: Option<i64>,
+        max_delta_per_sec: Option<f64>,
+    ) -> Self {
+        Self {
+            source,
+            name: name.to_string(),
+            valid_min,
+            valid_max,
+            max_delta_per_sec,
+            last: None,
+            pending: None,
+            trend: 0,
+        }
+    }
+
+    fn plausible(&self, from: (i64, Instant), value: i64, now: Instant) -> bool {
+        let max_delta = match self.max_delta_per_sec {
+            Some(max_delta) => max_delta,
+            None => return true,
+        };
+
+        let (from, at) = from;
+        let secs = now.duration_since(at).as_secs_f64();
+
+        (value - from).abs() as f64 <= max_delta * secs
+    }
+}
+
+impl Source for PlausibleSource {
+    fn get(&mut self, timeout: Duration) -> Result<i64, Error> {
+        let value = self.source.get(timeout)?;
+
+        if self.valid_min.is_some_and(|min| value < min)
+            || self.valid_max.is_some_and(|max| value > max)
+        {
+            return Err(Error::new(
+                error::Code::OutOfRange,
+                format!(
+                    "Reading {} of {} is outside of {}..{}",
+                    value,
+                    self.name,
+                    self.valid_min.map(|v| v.to_string()).unwrap_or_default(),
+                    self.valid_max.map(|v| v.to_string()).unwrap_or_default()
+                ),
+            ));
+        }
+
+        let now = Instant::now();
+
+        // a jump is held for a single poll, the reading after it is taken whatever it is,
+        // so a real rise faster than max_delta_per_sec is at most one poll late
+        let accepted = match (self.last, self.pending) {
+            (None, _) => true,
+            (Some(last), _) if self.plausible(last, value, now) => {
+                self.trend = 0;
+                true
+            }
+            (Some((last, _)), _) if self.trend != 0 && (value - last).signum() == self.trend => {
+                true
+            }
+            (Some((last, _)), Some((pending, _))) => {
+                // moving on in the direction of the held reading confirms the jump
+                let direction = (value - last).signum();
+                self.trend = if direction == (pending - last).signum() {
+                    direction
+                } else {
+                    0
+                };
+                true
+            }
+            _ => false,
+        };
+
+        if accepted {
+            self.last = Some((value, now));
+            self.pending = None;
+            return Ok(value);
+        }
+
+        self.trend = 0;
+        self.pending = Some((value, now));
+
+        Ok(self.last.map(|(last, _)| last).unwrap_or(value))
+    }
+
+    fn label(&self) -> Option<String> {
+        match self.pending {
+            Some((pending, _)) => Some(format!("held, read {}", pending)),
+            None => self.source.label(),
+        }
+    }
+
+    // the rejected reading while it is held
+    fn raw(&self) -> Option<i64> {
+        match self.pending {
+            Some((pending, _)) => Some(pending),
+            None => self.source.raw(),
+        }
+    }
+}
+
+// minuend - subtrahend, e.g. coolant over ambient temperature
+pub struct DifferenceSource {
+    minuend: Box<dyn Source>,
+    subtrahend: Box<dyn Source>,
+    last: Option<(i64, i64)>,
+}
+
+impl DifferenceSource {
+    pub fn new(minuend: Box<dyn Source>, subtrahend: Box<dyn Source>) -> Self {
+        Self {
+            minuend,
+            subtrahend,
+            last: None,
+        }
+    }
+}
+
+impl Source for DifferenceSource {
+    fn get(&mut self, timeout: Duration) -> Result<i64, Error> {
+        let side = |name: &str, err: Error| {
+            Error::new(
+                err.code(),
+                format!("{} of the difference failed: {}", name, err.message()),
+            )
+        };
+
+        self.last = None;
+
+        let minuend = self
+            .minuend
+            .get(timeout / 2)
+            .map_err(|err| side("Minuend", err))?;
+        let subtrahend = self
+            .subtrahend
+            .get(timeout / 2)
+            .map_err(|err| side("Subtrahend", err))?;
+
+        self.last = Some((minuend, subtrahend));
+
+        Ok(minuend - subtrahend)
+    }
+
+    fn label(&self) -> Option<String> {
+        self.last
+            .map(|(minuend, subtrahend)| format!("{} - {}", minuend, subtrahend))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct SharedConfig {
+    pub name: String,
+    pub interval: u64,
+    #[serde(rename = "type")]
+    pub src_type: String,
+    pub path: String,
+    pub args: Option<Vec<String>>,
+    // in milliseconds, readings older than this are considered stale, defaults to 3 intervals
+    pub max_age: Option<u64>,
+    #[serde(flatten)]
+    pub checks: Checks,
+}
+
+struct Reading {
+    value: i64,
+    time: Instant,
+}
+
+// polled once on its own interval, controls only read the cached value
+pub struct SharedSource {
+    name: String,
+    source: Mutex<Box<dyn Source>>,
+    interval: Duration,
+    max_age: Duration,
+    reading: Mutex<Option<Reading>>,
+}
+
+impl SharedSource {
+    pub fn new(config: &SharedConfig, shared: &Registry) -> Result<Self, Error> {
+        let source = create(&config.src_type, &config.path, config.args.as_ref(), shared)?;
+        let source = config
+            .checks
+            .apply(source, &config.src_type, &config.path)?;
+
+        Ok(Self {
+            name: config.name.clone(),
+            source: Mutex::new(source),
+            interval: Duration::from_millis(config.interval),
+            max_age: Duration::from_millis(config.max_age.unwrap_or(config.interval * 3)),
+            reading: Mutex::new(None),
+        })
+    }
+
+    pub fn name(&self) -> &String {
+        &self.name
+    }
+
+    pub fn get_interval(&self) -> &Duration {
+        &self.interval
+    }
+
+    pub fn poll(&self) -> Result<(), Error> {
+        let value = self.source.lock().unwrap().get(self.interval / 2)?;
+
+        *self.reading.lock().unwrap() = Some(Reading {
+            value,
+            time: Instant::now(),
+        });
+
+        Ok(())
+    }
+
+    pub fn cached(&self) -> Result<i64, Error> {
+        match self.reading.lock().unwrap().as_ref() {
+            Some(reading) if reading.time.elapsed() <= self.max_age => Ok(reading.value),
+            Some(_) => Err(Error::new(
+                error::Code::StaleReading,
+                format!("Reading of shared source {} is stale", self.name),
+            )),
+            None => Err(Error::new(
+                error::Code::StaleReading,
+                format!("Shared source {} has no reading yet", self.name),
+            )),
+        }
+    }
+}
+
+pub struct CachedSource {
+    shared: Arc<SharedSource>,
+}
+
+impl CachedSource {
+    pub fn new(shared: Arc<SharedSource>) -> Self {
+        Self { shared }
+    }
+}
+
+impl Source for CachedSource {
+    fn get(&mut self, _timeout: Duration) -> Result<i64, Error> {
+        self.shared.cached()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{collections::VecDeque, path::PathBuf, process};
+
+    // returns the queued readings one after the other
+    struct FakeSource {
+        readings: VecDeque<Result<i64, Error>>,
+    }
+
+    fn fake(readings: &[i64]) -> Box<dyn Source> {
+        Box::new(FakeSource {
+            readings: readings.iter().map(|reading| Ok(*reading)).collect(),
+        })
+    }
+
+    impl Source for FakeSource {
+        fn get(&mut self, _timeout: Duration) -> Result<i64, Error> {
+            self.readings.pop_front().unwrap_or_else(|| {
+                Err(Error::new(
+                    error::Code::General,
+                    String::from("No more readings"),
+                ))
+            })
+        }
+    }
+
+    fn get(source: &mut dyn Source) -> i64 {
+        source.get(Duration::from_millis(100)).unwrap()
+    }
+
+    fn plausible(readings: &[i64]) -> PlausibleSource {
+        PlausibleSource::new(
+            fake(readings),
+            "temp",
+            Some(-20000),
+            Some(150000),
+            Some(1000.0),
+        )
+    }
+
+    // stand-in for /proc or /sys
+    struct Fixture {
+        dir: PathBuf,
+    }
+
+    impl Fixture {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("kool-source-{}-{}", process::id(), name));
+
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+
+            Self { dir }
+        }
+
+        fn write(&self, file: &str, content: &str) -> String {
+            let path = self.dir.join(file);
+
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, content).unwrap();
+
+            path.to_string_lossy().into_owned()
+        }
+
+        fn path(&self) -> String {
+            self.dir.to_string_lossy().into_owned()
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn holds_a_single_spike() {
+        let mut source = plausible(&[40000, 90000, 40000]);
+
+        assert_eq!(get(&mut source), 40000);
+
+        assert_eq!(get(&mut source), 40000);
+        assert_eq!(source.raw(), Some(90000));
+        assert_eq!(source.label().as_deref(), Some("held, read 90000"));
+
+        assert_eq!(get(&mut source), 40000);
+        assert_eq!(source.raw(), None);
+        assert_eq!(source.label(), None);
+    }
+
+    #[test]
+    fn follows_a_sustained_rise_one_poll_late() {
+        let mut source = plausible(&[40000, 60000, 80000, 100000, 120000, 140000]);
+
+        assert_eq!(get(&mut source), 40000);
+        assert_eq!(get(&mut source), 40000);
+        assert_eq!(get(&mut source), 80000);
+        assert_eq!(get(&mut source), 100000);
+        assert_eq!(get(&mut source), 120000);
+        assert_eq!(get(&mut source), 140000);
+        assert_eq!(source.raw(), None);
+    }
+
+    #[test]
+    fn releases_the_hold_after_one_poll() {
+        let mut source = plausible(&[40000, 90000, 10000, 80000, 80000]);
+
+        assert_eq!(get(&mut source), 40000);
+        assert_eq!(get(&mut source), 40000);
+        // contradicts the held jump, but is taken all the same
+        assert_eq!(get(&mut source), 10000);
+        // no trend was confirmed, so the next jump is held again
+        assert_eq!(get(&mut source), 10000);
+        assert_eq!(get(&mut source), 80000);
+    }
+
+    #[test]
+    fn rejects_readings_out_of_range() {
+        let mut source = plausible(&[40000, -127000, 41000]);
+
+        assert_eq!(get(&mut source), 40000);
+
+        let err = source.get(Duration::from_millis(100)).unwrap_err();
+        assert_eq!(err.code(), error::Code::OutOfRange);
+    }
+
+    #[test]
+    fn reads_the_cpu_usage_since_the_previous_reading() {
+        let fixture = Fixture::new("cpu-usage");
+        let stat =
+            |cpu: &str, cpu1: &str| format!("{}\n{}\ncpu1 0 0 0 0\nintr 12345 0 0\n", cpu, cpu1);
+
+        let path = fixture.write(
+            "stat",
+            &stat("cpu  100 0 100 700 100 0 0 0 0 0", "cpu0 50 0 50 400 0"),
+        );
+
+        let mut all = CpuUsageSource::new(&path, None).unwrap();
+        let mut cpu0 = CpuUsageSource::new(&path, Some(&String::from("cpu0"))).unwrap();
+
+        // 100 jiffies, 25 of them idle or waiting on io
+        fixture.write(
+            "stat",
+            &stat("cpu  150 0 125 710 115 0 0 0 0 0", "cpu0 60 0 50 490 0"),
+        );
+
+        assert_eq!(get(&mut all), 75);
+        assert_eq!(get(&mut cpu0), 10);
+
+        // no jiffy passed, the last value stays
+        assert_eq!(get(&mut all), 75);
+
+        fixture.write(
+            "stat",
+            &stat("cpu  150 0 125 810 115 0 0 0 0 0", "cpu0 60 0 50 590 0"),
+        );
+
+        assert_eq!(get(&mut all), 0);
+    }
+
+    #[test]
+    fn refuses_a_missing_cpu_line() {
+        let fixture = Fixture::new("cpu-missing");
+        let path = fixture.write("stat", "cpu  100 0 100 700\ncpu0 50 0 50\n");
+
+        for line in ["cpu7", "cpu0"] {
+            let err = CpuUsageSource::new(&path, Some(&String::from(line))).err();
+
+            assert_eq!(err.map(|err| err.code()), Some(error::Code::UnableToParse));
+        }
+
+        let mut source = CpuUsageSource::new(&path, None).unwrap();
+        fixture.write("stat", "cpu  garbage\n");
+
+        assert_eq!(
+            source.get(Duration::ZERO).err().map(|err| err.code()),
+            Some(error::Code::UnableToParse)
+        );
+    }
+
+    #[test]
+    fn reads_the_load_average_times_100() {
+        let fixture = Fixture::new("loadavg");
+        let path = fixture.write("loadavg", "0.52 1.25 2.00 3/456 7890\n");
+
+        for (minutes, load) in [(None, 52), (Some("5"), 125), (Some("15"), 200)] {
+            let minutes = minutes.map(String::from);
+            let mut source = LoadAvgSource::new(&path, minutes.as_ref()).unwrap();
+
+            assert_eq!(get(&mut source), load);
+        }
+
+        let err = LoadAvgSource::new(&path, Some(&String::from("10"))).err();
+        assert_eq!(
+            err.map(|err| err.code()),
+            Some(error::Code::InvalidSourceConfig)
+        );
+
+        let mut source = LoadAvgSource::new(&path, None).unwrap();
+        fixture.write("loadavg", "\n");
+
+        assert_eq!(
+            source.get(Duration::ZERO).err().map(|err| err.code()),
+            Some(error::Code::UnableToParse)
+        );
+    }
+
+    #[test]
+    fn reads_the_highest_frequency_of_the_cores() {
+        let fixture = Fixture::new("cpu-freq");
+
+        fixture.write("cpu0/cpufreq/scaling_cur_freq", "1200000\n");
+        fixture.write("cpu1/cpufreq/scaling_cur_freq", "3400000\n");
+        // not cores, or cores without cpufreq
+        fixture.write("cpufreq/scaling_cur_freq", "9900000\n");
+        fixture.write("cpuidle/scaling_cur_freq", "9900000\n");
+        fixture.write("cpu2/online", "0\n");
+
+        let mut source = CpuFreqSource::new(&fixture.path()).unwrap();
+
+        assert_eq!(get(&mut source), 3400);
+
+        fixture.write("cpu1/cpufreq/scaling_cur_freq", "800000\n");
+
+        assert_eq!(get(&mut source), 1200);
+    }
+
+    #[test]
+    fn refuses_a_directory_without_cores() {
+        let fixture = Fixture::new("cpu-freq-empty");
+
+        fixture.write("cpu0/online", "1\n");
+
+        let err = CpuFreqSource::new(&fixture.path()).err();
+        assert_eq!(
+            err.map(|err| err.code()),
+            Some(error::Code::InvalidSourceConfig)
+        );
+    }
+
+    fn failing(readings: Vec<Result<i64, Error>>) -> Box<dyn Source> {
+        Box::new(FakeSource {
+            readings: readings.into(),
+        })
+    }
+
+    #[test]
+    fn subtracts_the_subtrahend_from_the_minuend() {
+        let mut source = DifferenceSource::new(fake(&[35000, 30000]), fake(&[25000, 32000]));
+
+        assert_eq!(source.label(), None);
+
+        assert_eq!(get(&mut source), 10000);
+        assert_eq!(source.label().as_deref(), Some("35000 - 25000"));
+
+        // colder than ambient goes below zero
+        assert_eq!(get(&mut source), -2000);
+        assert_eq!(source.label().as_deref(), Some("30000 - 32000"));
+    }
+
+    #[test]
+    fn names_the_side_of_the_difference_that_failed() {
+        let timeout = || Err(Error::new(error::Code::Timeout, String::from("No reading")));
+
+        let mut source = DifferenceSource::new(
+            failing(vec![Ok(35000), timeout(), Ok(36000)]),
+            // not read once the minuend failed
+            failing(vec![Ok(25000), timeout()]),
+        );
+
+        assert_eq!(get(&mut source), 10000);
+
+        let err = source.get(Duration::ZERO).unwrap_err();
+        assert_eq!(err.code(), error::Code::Timeout);
+        assert_eq!(
+            err.message(),
+            "Minuend of the difference failed: No reading"
+        );
+        // no label for a failed reading
+        assert_eq!(source.label(), None);
+
+        let err = source.get(Duration::ZERO).unwrap_err();
+        assert_eq!(err.code(), error::Code::Timeout);
+        assert_eq!(
+            err.message(),
+            "Subtrahend of the difference failed: No reading"
+        );
+        assert_eq!(source.label(), None);
+    }
+
+    fn stale(
+        readings: &[i64],
+        max_unchanged_polls: Option<u32>,
+        stale_after: Option<Duration>,
+        mtime_path: Option<String>,
+    ) -> (StaleSource, Arc<Mutex<Instant>>) {
+        let now = Arc::new(Mutex::new(Instant::now()));
+        let clock = Arc::clone(&now);
+
+        let source = StaleSource::with_clock(
+            fake(readings),
+            "temp",
+            max_unchanged_polls,
+            stale_after,
+            mtime_path,
+            Box::new(move || *clock.lock().unwrap()),
+        );
+
+        (source, now)
+    }
+
+    fn stale_code(source: &mut dyn Source) -> Option<error::Code> {
+        source.get(Duration::ZERO).err().map(|err| err.code())
+    }
+
+    #[test]
+    fn is_stale_after_too_many_unchanged_polls() {
+        let (mut source, _) = stale(&[40000, 40000, 40000, 41000, 41000], Some(2), None, None);
+
+        assert_eq!(get(&mut source), 40000);
+        assert_eq!(get(&mut source), 40000);
+        assert_eq!(stale_code(&mut source), Some(error::Code::StaleReading));
+
+        // a new value is fresh again
+        assert_eq!(get(&mut source), 41000);
+        assert_eq!(get(&mut source), 41000);
+    }
+
+    #[test]
+    fn is_stale_once_unchanged_for_stale_after() {
+        let (mut source, now) = stale(
+            &[40000, 40000, 40000, 41000],
+            None,
+            Some(Duration::from_secs(30)),
+            None,
+        );
+
+        assert_eq!(get(&mut source), 40000);
+
+        *now.lock().unwrap() += Duration::from_secs(29);
+        assert_eq!(get(&mut source), 40000);
+
+        *now.lock().unwrap() += Duration::from_secs(1);
+        assert_eq!(stale_code(&mut source), Some(error::Code::StaleReading));
+
+        assert_eq!(get(&mut source), 41000);
+    }
+
+    #[test]
+    fn compares_the_modification_time_instead_of_the_value() {
+        let fixture = Fixture::new("stale-mtime");
+        let path = fixture.write("temp", "40000\n");
+        let touch = |secs: u64| {
+            fs::File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+                .unwrap();
+        };
+
+        // the value changes but the file doesn't
+        let (mut source, _) = stale(
+            &[40000, 41000, 42000, 42000],
+            Some(2),
+            None,
+            Some(path.clone()),
+        );
+
+        touch(1000);
+        assert_eq!(get(&mut source), 40000);
+        assert_eq!(get(&mut source), 41000);
+        assert_eq!(stale_code(&mut source), Some(error::Code::StaleReading));
+
+        // the value doesn't change but the file was written
+        touch(2000);
+        assert_eq!(get(&mut source), 42000);
+    }
+}
@@ -0,0 +1,492 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::Write,
+    sync::Mutex,
+    time::UNIX_EPOCH,
+};
+
+use crate::error::{Code, Error};
+use crate::event::{Event, Payload};
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct TelemetryConfig {
+    pub path: String,
+    // csv or jsonl, defaults to csv
+    pub format: Option<String>,
+    // in bytes, the file is rotated once it grows past this, defaults to 10MB
+    pub max_size: Option<u64>,
+    // rotated files to keep, path.1 is the newest
+    pub keep: Option<u32>,
+    // only every nth tick of a control is recorded, defaults to 1
+    pub decimation: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct Row<'a> {
+    timestamp: f64,
+    control: &'a str,
+    // none when the source failed and the outputs are at default_dest_percent
+    raw: Option<i64>,
+    value: Option<i64>,
+    target: f64,
+    dest: &'a str,
+    pwm: i64,
+    rpm: Option<i64>,
+    label: Option<&'a str>,
+}
+
+#[derive(PartialEq)]
+enum Format {
+    Csv,
+    Jsonl,
+}
+
+struct Reading {
+    raw: Option<i64>,
+    value: Option<i64>,
+    label: Option<String>,
+    tick: u64,
+    // destinations already recorded with this reading
+    dests: HashSet<String>,
+}
+
+struct State {
+    file: File,
+    size: u64,
+    readings: HashMap<String, Reading>,
+}
+
+// records every reading and output of the controls, hooked in as a signal listener
+pub struct Recorder {
+    path: String,
+    format: Format,
+    max_size: u64,
+    keep: u32,
+    decimation: u64,
+    state: Mutex<State>,
+}
+
+impl Recorder {
+    const CSV_HEADER: &'static str = "timestamp,control,raw,value,target,dest,pwm,rpm,label\n";
+
+    pub fn new(config: &TelemetryConfig) -> Result<Self, Error> {
+        let format = match config.format.as_deref().unwrap_or("csv") {
+            "csv" => Format::Csv,
+            "jsonl" => Format::Jsonl,
+            format => {
+                return Err(Error::new(
+                    Code::InvalidTelemetryConfig,
+                    format!("Unknown telemetry format: {}", format),
+                ))
+            }
+        };
+
+        let (file, size) = Recorder::open(&config.path, &format)?;
+
+        Ok(Self {
+            path: config.path.clone(),
+            format,
+            max_size: config.max_size.unwrap_or(10 * 1024 * 1024),
+            keep: config.keep.unwrap_or(3),
+            decimation: config.decimation.unwrap_or(1).max(1),
+            state: Mutex::new(State {
+                file,
+                size,
+                readings: HashMap::new(),
+            }),
+        })
+    }
+
+    fn open(path: &String, format: &Format) -> Result<(File, u64), Error> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|err| {
+                Error::new(
+                    Code::InvalidTelemetryConfig,
+                    format!("Cannot open telemetry file {}: {}", path, err),
+                )
+            })?;
+
+        let mut size = file.metadata().map(|m| m.len()).unwrap_or(0);
+
+        if size == 0 && *format == Format::Csv {
+            let _ = file.write_all(Recorder::CSV_HEADER.as_bytes());
+            size = Recorder::CSV_HEADER.len() as u64;
+        }
+
+        Ok((file, size))
+    }
+
+    fn rotate(&self, state: &mut State) {
+        for i in (1..self.keep).rev() {
+            let _ = fs::rename(
+                format!("{}.{}", self.path, i),
+                format!("{}.{}", self.path, i + 1),
+            );
+        }
+
+        if self.keep > 0 {
+            let _ = fs::rename(&self.path, format!("{}.1", self.path));
+        } else {
+            let _ = fs::remove_file(&self.path);
+        }
+
+        if let Ok((file, size)) = Recorder::open(&self.path, &self.format) {
+            state.file = file;
+            state.size = size;
+        }
+    }
+
+    fn line(&self, row: &Row) -> String {
+        match self.format {
+            Format::Csv => {
+                // names with commas or quotes need to be quoted
+                let quote = |field: &str| {
+                    if field.contains([',', '"', '\n']) {
+                        format!("\"{}\"", field.replace('"', "\"\""))
+                    } else {
+                        field.to_string()
+                    }
+                };
+
+                let optional =
+                    |value: Option<i64>| value.map(|v| v.to_string()).unwrap_or_default();
+
+                format!(
+                    "{:.3},{},{},{},{:.1},{},{},{},{}\n",
+                    row.timestamp,
+                    quote(row.control),
+                    optional(row.raw),
+                    optional(row.value),
+                    row.target,
+                    quote(row.dest),
+                    row.pwm,
+                    optional(row.rpm),
+                    row.label.map(quote).unwrap_or_default()
+                )
+            }
+            Format::Jsonl => serde_json::to_string(row).unwrap_or_default() + "\n",
+        }
+    }
+
+    fn failed_reading() -> Reading {
+        Reading {
+            raw: None,
+            value: None,
+            label: None,
+            tick: 0,
+            dests: HashSet::new(),
+        }
+    }
+
+    pub fn record(&self, event: &Event) {
+        let control = match &event.origin {
+            Some(origin) => origin,
+            None => return,
+        };
+
+        let mut state = self.state.lock().unwrap();
+
+        match &event.payload {
+            Payload::Reading { raw, value, label } => {
+                let tick = state.readings.get(control).map(|r| r.tick + 1).unwrap_or(0);
+
+                state.readings.insert(
+                    control.clone(),
+                    Reading {
+                        raw: Some(*raw),
+                        value: Some(*value),
+                        label: label.clone(),
+                        tick,
+                        dests: HashSet::new(),
+                    },
+                );
+            }
+            Payload::Output {
+                dest,
+                target,
+                pwm,
+                rpm,
+                ..
+            } => {
+                let reading = state
+                    .readings
+                    .entry(control.clone())
+                    .or_insert_with(Recorder::failed_reading);
+
+                // a failing source sends no reading, a second output to the same destination
+                // is the next tick and doesn't belong to the last reading
+                if !reading.dests.insert(dest.clone()) {
+                    let tick = reading.tick + 1;
+
+                    *reading = Recorder::failed_reading();
+                    reading.tick = tick;
+                    reading.dests.insert(dest.clone());
+                }
+
+                if reading.tick % self.decimation != 0 {
+                    return;
+                }
+
+                let (raw, value, label) = (reading.raw, reading.value, reading.label.clone());
+
+                let timestamp = event
+                    .timestamp
+                    .duration_since(UNIX_EPOCH)
+                    .map(|t| t.as_secs_f64())
+                    .unwrap_or(0.0);
+
+                let line = self.line(&Row {
+                    timestamp,
+                    control,
+                    raw,
+                    value,
+                    target: *target,
+                    dest,
+                    pwm: *pwm,
+                    rpm: *rpm,
+                    label: label.as_deref(),
+                });
+
+                if state.file.write_all(line.as_bytes()).is_ok() {
+                    state.size += line.len() as u64;
+                }
+
+                if state.size >= self.max_size {
+                    self.rotate(&mut state);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::Severity;
+    use std::{path::PathBuf, process, time::Duration};
+
+    struct Fixture {
+        dir: PathBuf,
+        path: String,
+        recorder: Recorder,
+    }
+
+    impl Fixture {
+        fn new(name: &str, format: &str, max_size: Option<u64>, decimation: Option<u64>) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("kool-telemetry-{}-{}", process::id(), name));
+
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+
+            let path = dir.join("telemetry").to_string_lossy().into_owned();
+
+            let recorder = Recorder::new(&TelemetryConfig {
+                path: path.clone(),
+                format: Some(String::from(format)),
+                max_size,
+                keep: Some(2),
+                decimation,
+            })
+            .unwrap();
+
+            Self {
+                dir,
+                path,
+                recorder,
+            }
+        }
+
+        fn reading(&self, control: &str, raw: i64, label: Option<&str>) {
+            self.record(
+                control,
+                Payload::Reading {
+                    raw,
+                    value: raw / 1000,
+                    label: label.map(String::from),
+                },
+            );
+        }
+
+        fn output(&self, control: &str, dest: &str, pwm: i64) {
+            self.record(
+                control,
+                Payload::Output {
+                    dest: String::from(dest),
+                    target: 50.0,
+                    percent: 50.0,
+                    pwm,
+                    rpm: Some(900),
+                },
+            );
+        }
+
+        fn record(&self, control: &str, payload: Payload) {
+            let mut event = Event::new(Severity::Info, payload).with_origin(control);
+            event.timestamp = UNIX_EPOCH + Duration::from_millis(1000500);
+
+            self.recorder.record(&event);
+        }
+
+        fn lines(&self, suffix: &str) -> Vec<String> {
+            fs::read_to_string(format!("{}{}", self.path, suffix))
+                .unwrap()
+                .lines()
+                .map(String::from)
+                .collect()
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn records_every_output_with_the_last_reading() {
+        let fixture = Fixture::new("reading", "csv", None, None);
+
+        fixture.reading("cpu", 45000, Some("warm"));
+        fixture.output("cpu", "pwm1", 128);
+        fixture.output("cpu", "pwm2", 100);
+
+        assert_eq!(
+            fixture.lines(""),
+            [
+                "timestamp,control,raw,value,target,dest,pwm,rpm,label",
+                "1000.500,cpu,45000,45,50.0,pwm1,128,900,warm",
+                "1000.500,cpu,45000,45,50.0,pwm2,100,900,warm",
+            ]
+        );
+    }
+
+    #[test]
+    fn records_outputs_without_a_reading_as_a_failed_tick() {
+        let fixture = Fixture::new("failed", "csv", None, None);
+
+        // the source failed from the start
+        fixture.output("cpu", "pwm1", 255);
+        fixture.reading("cpu", 45000, None);
+        fixture.output("cpu", "pwm1", 128);
+        // a second output to pwm1 without a reading in between
+        fixture.output("cpu", "pwm1", 255);
+
+        assert_eq!(
+            fixture.lines(""),
+            [
+                "timestamp,control,raw,value,target,dest,pwm,rpm,label",
+                "1000.500,cpu,,,50.0,pwm1,255,900,",
+                "1000.500,cpu,45000,45,50.0,pwm1,128,900,",
+                "1000.500,cpu,,,50.0,pwm1,255,900,",
+            ]
+        );
+    }
+
+    #[test]
+    fn records_only_every_nth_tick_of_each_control() {
+        let fixture = Fixture::new("decimation", "csv", None, Some(2));
+
+        for tick in 0..4 {
+            fixture.reading("cpu", 40000 + tick, None);
+            fixture.output("cpu", "pwm1", 100);
+            fixture.output("cpu", "pwm2", 100);
+        }
+
+        // a failed tick counts like any other
+        fixture.output("cpu", "pwm1", 255);
+        fixture.reading("cpu", 50000, None);
+        fixture.output("cpu", "pwm1", 100);
+
+        // the ticks of another control are counted on their own
+        fixture.reading("gpu", 60000, None);
+        fixture.output("gpu", "pwm3", 100);
+
+        assert_eq!(
+            fixture.lines(""),
+            [
+                "timestamp,control,raw,value,target,dest,pwm,rpm,label",
+                "1000.500,cpu,40000,40,50.0,pwm1,100,900,",
+                "1000.500,cpu,40000,40,50.0,pwm2,100,900,",
+                "1000.500,cpu,40002,40,50.0,pwm1,100,900,",
+                "1000.500,cpu,40002,40,50.0,pwm2,100,900,",
+                "1000.500,cpu,,,50.0,pwm1,255,900,",
+                "1000.500,gpu,60000,60,50.0,pwm3,100,900,",
+            ]
+        );
+    }
+
+    #[test]
+    fn quotes_csv_fields_with_commas_and_quotes() {
+        let fixture = Fixture::new("quoting", "csv", None, None);
+
+        fixture.reading("cpu, \"package\"", 45000, Some("warm, rising"));
+        fixture.output("cpu, \"package\"", "pwm1", 128);
+
+        assert_eq!(
+            fixture.lines("")[1],
+            "1000.500,\"cpu, \"\"package\"\"\",45000,45,50.0,pwm1,128,900,\"warm, rising\""
+        );
+    }
+
+    #[test]
+    fn writes_one_json_object_per_line() {
+        let fixture = Fixture::new("jsonl", "jsonl", None, None);
+
+        fixture.output("cpu", "pwm1", 255);
+        fixture.reading("cpu", 45000, Some("warm"));
+        fixture.output("cpu", "pwm1", 128);
+
+        let rows: Vec<serde_json::Value> = fixture
+            .lines("")
+            .iter()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+
+        assert_eq!(
+            rows,
+            [
+                serde_json::json!({
+                    "timestamp": 1000.5, "control": "cpu", "raw": null, "value": null,
+                    "target": 50.0, "dest": "pwm1", "pwm": 255, "rpm": 900, "label": null,
+                }),
+                serde_json::json!({
+                    "timestamp": 1000.5, "control": "cpu", "raw": 45000, "value": 45,
+                    "target": 50.0, "dest": "pwm1", "pwm": 128, "rpm": 900, "label": "warm",
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn rotates_the_file_once_it_grows_past_max_size() {
+        // fits the header and two rows
+        let fixture = Fixture::new("rotation", "csv", Some(120), None);
+
+        for pwm in 100..106 {
+            fixture.reading("cpu", 45000, None);
+            fixture.output("cpu", "pwm1", pwm);
+        }
+
+        let header = "timestamp,control,raw,value,target,dest,pwm,rpm,label";
+        let row = |pwm: i64| format!("1000.500,cpu,45000,45,50.0,pwm1,{},900,", pwm);
+
+        // only keep rotated files are left, the newest one is path.1
+        assert_eq!(fixture.lines(""), [header.to_string()]);
+        assert_eq!(
+            fixture.lines(".1"),
+            [header.to_string(), row(104), row(105)]
+        );
+        assert_eq!(
+            fixture.lines(".2"),
+            [header.to_string(), row(102), row(103)]
+        );
+        assert!(!PathBuf::from(format!("{}.3", fixture.path)).exists());
+    }
+}
@@ -0,0 +1,143 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use kool::{signal::Signal, Code, Context, ControlBuilder, Emitter, Error, Payload, Source};
+
+// hands out whatever the test set last
+struct SharedReading {
+    reading: Arc<Mutex<Option<i64>>>,
+}
+
+impl Source for SharedReading {
+    fn get(&mut self, _: Duration) -> Result<i64, Error> {
+        self.reading
+            .lock()
+            .unwrap()
+            .ok_or_else(|| Error::new(Code::UnableToParse, String::from("sensor is gone")))
+    }
+}
+
+struct Fixture {
+    dir: PathBuf,
+    reading: Arc<Mutex<Option<i64>>>,
+    outputs: Arc<Mutex<Vec<(String, i64)>>>,
+    emitter: Emitter,
+}
+
+impl Fixture {
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("kool-it-{}-{}", process::id(), name));
+
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let outputs = Arc::new(Mutex::new(Vec::new()));
+        let mut signal = Signal::new();
+        let recorded = Arc::clone(&outputs);
+
+        signal.connect(move |e| {
+            if let Payload::Output { dest, pwm, .. } = &e.payload {
+                recorded.lock().unwrap().push((dest.clone(), *pwm));
+            }
+        });
+
+        Self {
+            dir,
+            reading: Arc::new(Mutex::new(None)),
+            outputs,
+            emitter: Emitter::new(Some(String::from("fan")), Arc::new(Mutex::new(signal))),
+        }
+    }
+
+    fn dest(&self, name: &str) -> String {
+        let dest = self.dir.join(name);
+
+        fs::write(&dest, "0").unwrap();
+        dest.to_string_lossy().into_owned()
+    }
+
+    fn source(&self) -> Box<dyn Source> {
+        Box::new(SharedReading {
+            reading: Arc::clone(&self.reading),
+        })
+    }
+
+    fn set(&self, reading: Option<i64>) {
+        *self.reading.lock().unwrap() = reading;
+    }
+
+    fn pwm(&self, name: &str) -> String {
+        fs::read_to_string(Path::new(&self.dir).join(name))
+            .unwrap()
+            .trim()
+            .to_string()
+    }
+
+    fn outputs(&self) -> Vec<(String, i64)> {
+        self.outputs.lock().unwrap().drain(..).collect()
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[test]
+fn writes_the_curve_of_a_custom_source_to_every_destination() {
+    let fixture = Fixture::new("curve");
+    let (pwm1, pwm2) = (fixture.dest("pwm1"), fixture.dest("pwm2"));
+
+    let mut control = ControlBuilder::new("fan")
+        .custom_source(fixture.source())
+        .dest(&pwm1, 0, 255)
+        .dest(&pwm2, 60, 200)
+        .points(&[(40000, 20), (80000, 100)])
+        .build(&Context::default())
+        .unwrap();
+
+    fixture.set(Some(60000));
+    control.control(&fixture.emitter).unwrap();
+
+    assert_eq!(fixture.pwm("pwm1"), "153");
+    assert_eq!(fixture.pwm("pwm2"), "144");
+    assert_eq!(
+        fixture.outputs(),
+        [(pwm1.clone(), 153), (pwm2.clone(), 144)]
+    );
+
+    fixture.set(Some(80000));
+    control.control(&fixture.emitter).unwrap();
+
+    assert_eq!(fixture.pwm("pwm1"), "255");
+    assert_eq!(fixture.pwm("pwm2"), "200");
+    assert_eq!(fixture.outputs(), [(pwm1, 255), (pwm2, 200)]);
+}
+
+#[test]
+fn writes_the_default_percent_while_a_custom_source_fails() {
+    let fixture = Fixture::new("failing");
+    let pwm1 = fixture.dest("pwm1");
+
+    let mut control = ControlBuilder::new("fan")
+        .custom_source(fixture.source())
+        .dest(&pwm1, 0, 200)
+        .points(&[(40000, 20), (80000, 100)])
+        .default_percent(70)
+        .build(&Context::default())
+        .unwrap();
+
+    assert!(control.control(&fixture.emitter).is_err());
+    assert_eq!(fixture.pwm("pwm1"), "140");
+
+    fixture.set(Some(40000));
+    control.control(&fixture.emitter).unwrap();
+
+    assert_eq!(fixture.pwm("pwm1"), "40");
+}